@@ -0,0 +1,543 @@
+use ray_tracer_interface::{
+    camera::Camera,
+    color::{self, Color},
+    lights::Light,
+    scene::Background,
+    ImageSlice, RenderMeta, WorkItem,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
+
+/// A job fails once one of its tiles had to be taken back this many times.
+const MAX_TILE_RETRIES: u32 = 3;
+
+/// What a job renders. The world itself is stored once under its hash and
+/// shared with every other job using the same one.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JobScene {
+    pub world: String,
+    pub camera: Camera,
+    pub background: Background,
+    pub lights: Vec<Light>,
+}
+
+struct Assignment {
+    slave: Uuid,
+    assigned_at: Instant,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// No slave has picked up any of it yet.
+    Queued,
+    Rendering,
+    Done,
+    Failed,
+}
+
+/// How much a slave contributed to a job and how long it took.
+#[derive(Serialize, Default, Clone)]
+pub struct SlaveTiming {
+    passes: u32,
+    busy_secs: f32,
+    secs_per_pass: f32,
+}
+
+#[derive(Serialize)]
+pub struct JobStatus {
+    id: Uuid,
+    state: JobState,
+    width: u32,
+    height: u32,
+    samples_per_pixel: u32,
+    tiles_completed: usize,
+    tiles_total: u32,
+    /// Share of all samples rendered so far, from 0 to 1.
+    progress: f32,
+    elapsed_secs: Option<f32>,
+    eta_secs: Option<f32>,
+    error: Option<String>,
+    slaves: HashMap<Uuid, SlaveTiming>,
+}
+
+/// The part of a job that outlives the controller, besides the passes it got.
+#[derive(Serialize, Deserialize)]
+pub struct JobRecord {
+    pub render_meta: RenderMeta,
+    pub scene: JobScene,
+    pub created_at: SystemTime,
+    pub started_at: Option<SystemTime>,
+    pub finished_at: Option<SystemTime>,
+    pub failed: Option<String>,
+}
+
+pub struct Job {
+    pub render_meta: RenderMeta,
+    pub scene: JobScene,
+    /// Tiles with a pass out on a slave. A tile only has one pass out at a
+    /// time, since passes have to arrive in order.
+    assignments: HashMap<u32, Assignment>,
+    /// Sample counts of the passes each tile is rendered in.
+    pass_samples: Vec<u32>,
+    /// Sum of every accepted sample's radiance, row-major from the top.
+    accumulated: Vec<Color>,
+    /// Samples per pixel accumulated and passes accepted, per tile.
+    samples: Vec<u32>,
+    passes: Vec<u32>,
+    /// Times each tile was taken back from a slave.
+    retries: Vec<u32>,
+    created_at: SystemTime,
+    started_at: Option<SystemTime>,
+    /// When the job was done or failed.
+    finished_at: Option<SystemTime>,
+    failed: Option<String>,
+    timings: HashMap<Uuid, SlaveTiming>,
+    /// Whether anything in the record changed since it was last saved.
+    changed: bool,
+}
+
+impl Job {
+    pub fn new(render_meta: RenderMeta, scene: JobScene) -> Self {
+        let pixels = render_meta.width as usize * render_meta.height as usize;
+        let tiles = render_meta.tile_count() as usize;
+        Self {
+            scene,
+            assignments: HashMap::new(),
+            pass_samples: render_meta.passes(),
+            accumulated: vec![color::BLACK; pixels],
+            samples: vec![0; tiles],
+            passes: vec![0; tiles],
+            retries: vec![0; tiles],
+            created_at: SystemTime::now(),
+            started_at: None,
+            finished_at: None,
+            failed: None,
+            timings: HashMap::new(),
+            changed: true,
+            render_meta,
+        }
+    }
+
+    /// Recreates a saved job. Its passes are added back separately.
+    pub fn restore(record: JobRecord) -> Self {
+        Self {
+            created_at: record.created_at,
+            started_at: record.started_at,
+            finished_at: record.finished_at,
+            failed: record.failed,
+            changed: false,
+            ..Self::new(record.render_meta, record.scene)
+        }
+    }
+
+    /// Fills in a finished job from its final radiance, as returned by
+    /// `radiance`.
+    pub fn restore_radiance(&mut self, radiance: &[f32]) {
+        let samples = self.render_meta.samples_per_pixel;
+        for (sum, pixel) in self.accumulated.iter_mut().zip(radiance.chunks_exact(3)) {
+            *sum = Color::from_slice([pixel[0], pixel[1], pixel[2]]) * samples as f32;
+        }
+        self.samples.fill(samples);
+        self.passes.fill(self.pass_samples.len() as u32);
+    }
+
+    pub fn record(&self) -> JobRecord {
+        JobRecord {
+            render_meta: self.render_meta.clone(),
+            scene: self.scene.clone(),
+            created_at: self.created_at,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            failed: self.failed.clone(),
+        }
+    }
+
+    /// Returns whether the record needs saving, and assumes it will be.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    pub fn created_at(&self) -> SystemTime {
+        self.created_at
+    }
+
+    pub fn finished_at(&self) -> Option<SystemTime> {
+        self.finished_at
+    }
+
+    pub fn has_tile(&self, tile_no: u32) -> bool {
+        self.samples
+            .get(tile_no as usize)
+            .is_some_and(|samples| *samples >= self.render_meta.samples_per_pixel)
+    }
+
+    pub fn completed(&self) -> usize {
+        (0..self.render_meta.tile_count())
+            .filter(|i| self.has_tile(*i))
+            .count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed() == self.render_meta.tile_count() as usize
+    }
+
+    /// Hands the next pass of a tile to `slave`. Tiles that are furthest
+    /// behind go first, so every tile gets its first pass before any gets its
+    /// second and progressive previews fill in evenly.
+    pub fn claim(&mut self, slave: Uuid) -> Option<WorkItem> {
+        if self.failed.is_some() {
+            return None;
+        }
+        let tile_no = (0..self.render_meta.tile_count())
+            .filter(|i| !self.has_tile(*i) && !self.assignments.contains_key(i))
+            .min_by_key(|i| self.passes[*i as usize])?;
+        self.assignments.insert(
+            tile_no,
+            Assignment {
+                slave,
+                assigned_at: Instant::now(),
+            },
+        );
+        if self.started_at.is_none() {
+            self.started_at = Some(SystemTime::now());
+            self.changed = true;
+        }
+        let pass = self.passes[tile_no as usize];
+        Some(WorkItem {
+            render_meta: self.render_meta.clone(),
+            world: self.scene.world.clone(),
+            camera: self.scene.camera.clone(),
+            background: self.scene.background.clone(),
+            lights: self.scene.lights.clone(),
+            tile_no,
+            pass,
+            samples: self.pass_samples[pass as usize],
+        })
+    }
+
+    /// Adds a pass to the image. Passes are deterministic, so when a tile was
+    /// handed out twice only the first copy of each pass is kept; passes
+    /// arriving out of order or with other than the pass's sample count are
+    /// dropped too, as are any once the job failed. Returns whether it was
+    /// used.
+    pub fn add_pass(&mut self, slice: &ImageSlice) -> bool {
+        let tile_no = slice.tile_no as usize;
+        if self.failed.is_some() || self.passes.get(tile_no) != Some(&slice.pass) {
+            return false;
+        }
+        if self.pass_samples.get(slice.pass as usize) != Some(&slice.samples) {
+            return false;
+        }
+        let tile = self.render_meta.tile(slice.tile_no);
+        if slice.image.len() != tile.width as usize * tile.height as usize * 3 {
+            return false;
+        }
+        let width = self.render_meta.width as usize;
+        for (row, pixels) in slice
+            .image
+            .chunks_exact(tile.width as usize * 3)
+            .enumerate()
+        {
+            let start = (tile.y as usize + row) * width + tile.x as usize;
+            for (sum, pixel) in self.accumulated[start..start + tile.width as usize]
+                .iter_mut()
+                .zip(pixels.chunks_exact(3))
+            {
+                *sum += Color::from_slice([pixel[0], pixel[1], pixel[2]]) * slice.samples as f32;
+            }
+        }
+        self.passes[tile_no] += 1;
+        self.samples[tile_no] += slice.samples;
+        // Whoever has the tile now would only send a copy. The time only
+        // counts if the pass is theirs, as a slave that was given up on may
+        // still come through.
+        if let Some(assignment) = self.assignments.remove(&slice.tile_no) {
+            if assignment.slave == slice.slave {
+                let timing = self.timings.entry(assignment.slave).or_default();
+                timing.passes += 1;
+                timing.busy_secs += assignment.assigned_at.elapsed().as_secs_f32();
+                timing.secs_per_pass = timing.busy_secs / timing.passes as f32;
+            }
+        }
+        if self.is_complete() && self.finished_at.is_none() {
+            self.finished_at = Some(SystemTime::now());
+            self.changed = true;
+        }
+        true
+    }
+
+    /// The image as it stands, as interleaved linear RGB radiance. Tiles
+    /// nothing has come back for yet are black.
+    pub fn radiance(&self) -> Vec<f32> {
+        let width = self.render_meta.width as usize;
+        let mut image = vec![0f32; self.accumulated.len() * 3];
+        for (i, (sum, pixel)) in self
+            .accumulated
+            .iter()
+            .zip(image.chunks_exact_mut(3))
+            .enumerate()
+        {
+            let tile_no = self
+                .render_meta
+                .tile_at((i % width) as u32, (i / width) as u32);
+            let samples = self.samples[tile_no as usize];
+            if samples > 0 {
+                let mean = *sum / samples as f32;
+                [pixel[0], pixel[1], pixel[2]] = [mean.r, mean.g, mean.b];
+            }
+        }
+        image
+    }
+
+    /// Takes tiles back from slaves that are gone or have had them for longer
+    /// than `timeout`, so the next slave asking for work picks them up.
+    /// Returns the tiles released.
+    pub fn release_overdue(
+        &mut self,
+        timeout: Duration,
+        is_alive: impl Fn(&Uuid) -> bool,
+    ) -> Vec<u32> {
+        let overdue: Vec<u32> = self
+            .assignments
+            .iter()
+            .filter(|(_, assignment)| {
+                !is_alive(&assignment.slave) || assignment.assigned_at.elapsed() > timeout
+            })
+            .map(|(tile_no, _)| *tile_no)
+            .collect();
+        for tile_no in &overdue {
+            self.assignments.remove(tile_no);
+            self.retries[*tile_no as usize] += 1;
+            if self.retries[*tile_no as usize] >= MAX_TILE_RETRIES && self.failed.is_none() {
+                self.fail(format!(
+                    "tile {} was given up on {} times",
+                    tile_no, MAX_TILE_RETRIES
+                ));
+            }
+        }
+        overdue
+    }
+
+    /// Stops the job for good, handing out none of its remaining tiles.
+    pub fn fail(&mut self, error: String) {
+        self.failed = Some(error);
+        self.finished_at = Some(SystemTime::now());
+        self.changed = true;
+    }
+
+    pub fn state(&self) -> JobState {
+        if self.failed.is_some() {
+            JobState::Failed
+        } else if self.is_complete() {
+            JobState::Done
+        } else if self.started_at.is_some() {
+            JobState::Rendering
+        } else {
+            JobState::Queued
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.failed.as_deref()
+    }
+
+    /// Share of all pixel samples of the job rendered so far.
+    fn progress(&self) -> f32 {
+        let done: u64 = (0..self.render_meta.tile_count())
+            .map(|i| {
+                let tile = self.render_meta.tile(i);
+                tile.width as u64 * tile.height as u64 * self.samples[i as usize] as u64
+            })
+            .sum();
+        let total = self.render_meta.width as u64
+            * self.render_meta.height as u64
+            * self.render_meta.samples_per_pixel as u64;
+        done as f32 / total as f32
+    }
+
+    pub fn status(&self) -> JobStatus {
+        let progress = self.progress();
+        let elapsed = self.started_at.map(|started_at| {
+            self.finished_at
+                .unwrap_or_else(SystemTime::now)
+                .duration_since(started_at)
+                .unwrap_or_default()
+        });
+        // Assumes the rest goes as fast as what has been rendered so far.
+        let eta = match (self.state(), elapsed) {
+            (JobState::Rendering, Some(elapsed)) if progress > 0f32 => {
+                Some(elapsed.as_secs_f32() * (1f32 - progress) / progress)
+            }
+            (JobState::Done, _) => Some(0f32),
+            _ => None,
+        };
+        JobStatus {
+            id: self.render_meta.id,
+            state: self.state(),
+            width: self.render_meta.width,
+            height: self.render_meta.height,
+            samples_per_pixel: self.render_meta.samples_per_pixel,
+            tiles_completed: self.completed(),
+            tiles_total: self.render_meta.tile_count(),
+            progress,
+            elapsed_secs: elapsed.map(|elapsed| elapsed.as_secs_f32()),
+            eta_secs: eta,
+            error: self.failed.clone(),
+            slaves: self.timings.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ray_tracer_interface::camera::CameraSettings;
+
+    /// A job of two 2x2 tiles with 5 samples per pixel.
+    fn job(progressive: bool) -> Job {
+        Job::new(
+            RenderMeta {
+                height: 2,
+                width: 4,
+                tile_size: 2,
+                samples_per_pixel: 5,
+                max_depth: 4,
+                seed: 1,
+                gamma: 2.2,
+                progressive,
+                id: Uuid::new_v4(),
+            },
+            JobScene {
+                world: String::new(),
+                camera: CameraSettings::default().build(4, 2),
+                background: Background::default(),
+                lights: Vec::new(),
+            },
+        )
+    }
+
+    /// What `slave` sends back for `work`, with every pixel `value`.
+    fn slice(work: &WorkItem, slave: Uuid, value: f32) -> ImageSlice {
+        ImageSlice {
+            tile_no: work.tile_no,
+            pass: work.pass,
+            samples: work.samples,
+            image: vec![value; 2 * 2 * 3],
+            id: work.render_meta.id,
+            slave,
+        }
+    }
+
+    #[test]
+    fn test_overdue_tiles_are_released() {
+        let mut job = job(false);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let first = job.claim(a).unwrap();
+        let second = job.claim(a).unwrap();
+        assert!(job.claim(b).is_none());
+        assert!(job
+            .release_overdue(Duration::from_secs(60), |_| true)
+            .is_empty());
+
+        // `a` is gone.
+        let mut released = job.release_overdue(Duration::from_secs(60), |slave| *slave != a);
+        released.sort();
+        assert_eq!(released, [first.tile_no, second.tile_no]);
+        let again = job.claim(b).unwrap();
+        assert_eq!((again.tile_no, again.pass), (first.tile_no, 0));
+
+        // `b` is too slow.
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(
+            job.release_overdue(Duration::from_millis(1), |_| true),
+            [again.tile_no]
+        );
+        assert_eq!(job.retries[again.tile_no as usize], 2);
+        assert_eq!(job.state(), JobState::Rendering);
+    }
+
+    #[test]
+    fn test_job_fails_after_max_retries() {
+        let mut job = job(false);
+        let slave = Uuid::new_v4();
+        for _ in 0..MAX_TILE_RETRIES {
+            assert!(job.error().is_none());
+            while job.claim(slave).is_some() {}
+            assert_eq!(
+                job.release_overdue(Duration::from_secs(60), |_| false)
+                    .len(),
+                2
+            );
+        }
+        assert_eq!(job.state(), JobState::Failed);
+        assert!(job.error().is_some());
+        assert!(job.finished_at().is_some());
+        assert!(job.claim(slave).is_none());
+    }
+
+    #[test]
+    fn test_late_duplicate_pass_is_ignored() {
+        let mut job = job(true);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let late = job.claim(a).unwrap();
+        job.release_overdue(Duration::from_secs(60), |slave| *slave != a);
+        let again = job.claim(b).unwrap();
+        assert_eq!((again.tile_no, again.pass), (late.tile_no, late.pass));
+
+        assert!(job.add_pass(&slice(&again, b, 1.0)));
+        assert!(!job.add_pass(&slice(&late, a, 2.0)));
+        assert_eq!(job.passes[late.tile_no as usize], 1);
+        assert_eq!(job.samples[late.tile_no as usize], 1);
+        assert_eq!(job.radiance()[..3], [1.0; 3]);
+        assert_eq!(job.timings[&b].passes, 1);
+        assert!(!job.timings.contains_key(&a));
+    }
+
+    #[test]
+    fn test_late_pass_from_reaped_slave() {
+        let mut job = job(true);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let late = job.claim(a).unwrap();
+        job.release_overdue(Duration::from_secs(60), |slave| *slave != a);
+        let again = job.claim(b).unwrap();
+
+        // Passes are deterministic, so the first copy is as good as any, but
+        // `b` didn't render it and `a` had been given up on.
+        assert!(job.add_pass(&slice(&late, a, 1.0)));
+        assert!(job.timings.is_empty());
+        // The tile's next pass is free for anyone, and `b`'s copy is dropped.
+        assert!(job.assignments.is_empty());
+        assert!(!job.add_pass(&slice(&again, b, 1.0)));
+        assert!(job.timings.is_empty());
+        assert_eq!(job.claim(b).unwrap().tile_no, 1 - late.tile_no);
+    }
+
+    #[test]
+    fn test_passes_must_match() {
+        let mut job = job(true);
+        let slave = Uuid::new_v4();
+        let work = job.claim(slave).unwrap();
+        let mut wrong = slice(&work, slave, 1.0);
+        wrong.samples = job.render_meta.samples_per_pixel;
+        assert!(!job.add_pass(&wrong));
+        wrong.samples = work.samples;
+        wrong.image.pop();
+        assert!(!job.add_pass(&wrong));
+        assert_eq!(job.samples[work.tile_no as usize], 0);
+        assert!(!job.has_tile(work.tile_no));
+        assert!(job.add_pass(&slice(&work, slave, 1.0)));
+
+        // Nothing counts once the job failed.
+        let next = job.claim(slave).unwrap();
+        for _ in 1..MAX_TILE_RETRIES {
+            job.release_overdue(Duration::from_secs(60), |_| false);
+            assert_eq!(job.claim(slave).unwrap().tile_no, next.tile_no);
+        }
+        job.release_overdue(Duration::from_secs(60), |_| false);
+        assert_eq!(job.state(), JobState::Failed);
+        assert!(!job.add_pass(&slice(&next, slave, 1.0)));
+    }
+}
@@ -1,159 +1,678 @@
+use actix_web::error::InternalError;
+use actix_web::http::{header, StatusCode};
 use actix_web::web::Bytes;
-use actix_web::{post, web, App, HttpServer, Responder};
-use image::{ImageBuffer, Rgb};
-use log::info;
-mod obj;
-use futures::future;
-use ray_tracer_interface::{ImageSlice, RenderInfo, RenderMeta};
-use reqwest::Client;
+use actix_web::{delete, get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use crossbeam_channel::Sender;
+use log::{error, info};
+mod jobs;
+mod slaves;
+mod store;
+use jobs::{Job, JobScene, JobState};
+use ray_tracer_interface::{
+    camera::{Camera, CameraSettings},
+    environment::EnvironmentMap,
+    environment_hash,
+    lights::Light,
+    obj,
+    output::{self, LdrSettings, OutputFormat, Tonemap},
+    render::WorldData,
+    scene::{Background, RenderSettings, Scene},
+    shapes::Object,
+    wire::{Encoding, WireError},
+    HeartbeatReply, ImageSlice, RenderMeta, SlaveRegistration, AABB, HEARTBEAT_INTERVAL,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::RwLock;
+use slaves::SlaveTable;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use store::{Change, JobStore};
 use uuid::Uuid;
 
-struct Job {
-    render_meta: RenderMeta,
-    result: Vec<ImageSlice>,
-}
-
 struct AppState {
     jobs: Vec<Job>,
+    slaves: SlaveTable,
+    /// Worlds of the current jobs along with their BVHs, by hash.
+    worlds: HashMap<String, Arc<WorldData>>,
+    /// Environment maps of the current jobs as uploaded, by hash.
+    environments: HashMap<String, Bytes>,
+    /// Jobs deleted while rendering, passed on to slaves with their
+    /// heartbeats until none of them can still be working on one.
+    cancelled: HashMap<Uuid, Instant>,
+    /// Changes to the job store, made in the order they are sent in.
+    store: Sender<Change>,
+}
+
+impl AppState {
+    fn store(&self, change: Change) {
+        if self.store.send(change).is_err() {
+            error!("the job store writer is gone");
+        }
+    }
+
+    /// Drops worlds and environment maps no job needs for rendering any more.
+    fn prune_worlds(&mut self) {
+        let rendering = || {
+            self.jobs
+                .iter()
+                .filter(|job| matches!(job.state(), JobState::Queued | JobState::Rendering))
+        };
+        let mut removed = Vec::new();
+        self.worlds.retain(|hash, _| {
+            let needed = rendering().any(|job| job.scene.world == *hash);
+            if !needed {
+                removed.push(Change::RemoveWorld(hash.clone()));
+            }
+            needed
+        });
+        self.environments.retain(|hash, _| {
+            let needed =
+                rendering().any(|job| job.scene.background.environment_map() == Some(hash));
+            if !needed {
+                removed.push(Change::RemoveEnvironment(hash.clone()));
+            }
+            needed
+        });
+        for change in removed {
+            self.store(change);
+        }
+    }
+
+    /// Writes out the records of jobs that changed since they were last saved.
+    fn save_changed(&mut self) {
+        let changed: Vec<_> = self
+            .jobs
+            .iter_mut()
+            .filter_map(|job| job.take_changed().then(|| job.record()))
+            .collect();
+        for record in changed {
+            self.store(Change::Job(Box::new(record)));
+        }
+    }
+}
+
+/// Slaves that miss this many heartbeats in a row are considered dead.
+const SLAVE_TIMEOUT: Duration = HEARTBEAT_INTERVAL.saturating_mul(3);
+
+/// Where jobs are kept, overridable through `JOB_STORE_DIR`.
+fn job_store_dir() -> String {
+    std::env::var("JOB_STORE_DIR").unwrap_or_else(|_| "jobs".to_owned())
+}
+
+/// How long finished jobs are kept, overridable through `JOB_RETENTION_SECS`.
+fn job_retention() -> Duration {
+    std::env::var("JOB_RETENTION_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(24 * 60 * 60))
+}
+
+/// How long a slave gets to return a pass before the tile is handed to another
+/// one, overridable through `TILE_TIMEOUT_SECS`.
+fn tile_timeout() -> Duration {
+    std::env::var("TILE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(600))
+}
+
+/// Per-upload render settings passed as query parameters, taking precedence
+/// over the defaults or whatever the scene file specifies.
+#[derive(Deserialize)]
+struct RenderOverrides {
+    samples_per_pixel: Option<u32>,
+    max_depth: Option<u32>,
+    seed: Option<u64>,
+    gamma: Option<f32>,
+    progressive: Option<bool>,
+}
+
+impl RenderOverrides {
+    fn apply(&self, render: &mut RenderSettings) {
+        if let Some(samples_per_pixel) = self.samples_per_pixel {
+            render.samples_per_pixel = samples_per_pixel;
+        }
+        if let Some(max_depth) = self.max_depth {
+            render.max_depth = max_depth;
+        }
+        if let Some(seed) = self.seed {
+            render.seed = Some(seed);
+        }
+        if let Some(gamma) = self.gamma {
+            render.gamma = gamma;
+        }
+        if let Some(progressive) = self.progressive {
+            render.progressive = progressive;
+        }
+    }
+}
+
+/// An upload turned into what a job renders.
+struct NewJob {
+    render: RenderSettings,
+    world: Vec<Object>,
+    camera: Camera,
+    background: Background,
+    environment: Option<(String, Bytes)>,
+    lights: Vec<Light>,
+}
+
+/// Queues the job `parse` makes out of an upload. Parsing and building the
+/// world's BVH take a while for large worlds, so they run on the blocking
+/// pool instead of holding up the workers slaves talk to.
+async fn queue_job(
+    state: &RwLock<AppState>,
+    parse: impl FnOnce() -> Result<NewJob, String> + Send + 'static,
+) -> HttpResponse {
+    let built = web::block(move || {
+        let mut job = parse()?;
+        let world = WorldData::build(std::mem::take(&mut job.world));
+        Ok::<_, String>((job, world))
+    })
+    .await;
+    let (job, world) = match built {
+        Ok(Ok(built)) => built,
+        Ok(Err(e)) => return HttpResponse::BadRequest().body(e),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let id = Uuid::new_v4();
+    let render_meta = RenderMeta {
+        height: job.render.height,
+        width: job.render.width,
+        tile_size: job.render.tile_size,
+        samples_per_pixel: job.render.samples_per_pixel,
+        max_depth: job.render.max_depth,
+        seed: job.render.seed.unwrap_or(id.as_u64_pair().0),
+        gamma: job.render.gamma,
+        progressive: job.render.progressive,
+        id,
+    };
+    let hash = world.hash();
+    // The world goes in along with the job needing it, so pruning never sees
+    // one without the other.
+    let mut state = state.write().unwrap();
+    if let Some(map) = job.background.environment_map() {
+        if job.environment.is_none() && !state.environments.contains_key(map) {
+            return HttpResponse::BadRequest().body(
+                "environment map missing, upload it after the scene to /scene/{scene_size}/",
+            );
+        }
+    }
+    let world = state
+        .worlds
+        .entry(hash.clone())
+        .or_insert_with(|| Arc::new(world))
+        .clone();
+    state.store(Change::World(hash.clone(), world));
+    if let Some((hash, data)) = job.environment {
+        state
+            .environments
+            .entry(hash.clone())
+            .or_insert_with(|| data.clone());
+        state.store(Change::Environment(hash, data));
+    }
+    state.jobs.push(Job::new(
+        render_meta,
+        JobScene {
+            world: hash,
+            camera: job.camera,
+            background: job.background,
+            lights: job.lights,
+        },
+    ));
+    state.save_changed();
+    HttpResponse::Ok().body(id.to_string())
 }
 
 #[post("/upload/{obj_size}/")]
 async fn index(
     body: Bytes,
     path: web::Path<usize>,
+    overrides: web::Query<RenderOverrides>,
     state: web::Data<RwLock<AppState>>,
-) -> impl Responder {
+) -> HttpResponse {
     info!("Got request");
     let obj_size = path.into_inner();
-    let body = body.to_vec();
-    let id = Uuid::new_v4();
-    let client = Client::new();
-    let divisions = 20;
-    let render_meta = RenderMeta {
-        height: 1080,
-        width: 1920,
-        divisions,
-        id,
-    };
-    let mut state = state.write().unwrap();
-    state.jobs.push(Job {
-        result: Vec::new(),
-        render_meta: render_meta.clone(),
-    });
+    let mut render = RenderSettings::default();
+    overrides.apply(&mut render);
+    if let Err(e) = render.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
     info!("metadata extraction complete");
-    let world = obj::build_world(body, obj_size);
-    future::join_all((0..divisions).map(|division_no| {
-        let client = &client;
-        let world = &world;
-        let render_meta = &render_meta;
-        async move {
-            info!("Dispatch to slave {}", division_no + 1);
-            info!(
-                "Response from slave: {}",
-                client
-                    .post("http://slave:8081")
-                    .body(
-                        json!(RenderInfo {
-                            division_no,
-                            render_meta: render_meta.clone(),
-                            world: world.clone(),
-                        })
-                        .to_string(),
-                    )
-                    .header(reqwest::header::CONTENT_TYPE, "application/json")
-                    .send()
-                    .await
-                    .unwrap()
-                    .text()
-                    .await
-                    .unwrap_or_default()
-            );
-        }
-    }))
-    .await;
+    if obj_size > body.len() {
+        return HttpResponse::BadRequest().body("obj size is larger than the upload");
+    }
+    queue_job(&state, move || {
+        let world =
+            obj::build_world(&body[..obj_size], &body[obj_size..]).map_err(|e| e.to_string())?;
+        let bounds = world
+            .iter()
+            .fold(AABB::empty(), |bounds, object| bounds.join_bounded(object));
+        let camera = CameraSettings::framing(&bounds).build(render.width, render.height);
+        Ok(NewJob {
+            render,
+            world,
+            camera,
+            background: Background::default(),
+            environment: None,
+            lights: Vec::new(),
+        })
+    })
+    .await
+}
+
+#[post("/scene")]
+async fn scene(
+    body: Bytes,
+    overrides: web::Query<RenderOverrides>,
+    state: web::Data<RwLock<AppState>>,
+) -> HttpResponse {
+    info!("Got scene");
+    submit_scene(state, body, None, overrides.into_inner()).await
+}
+
+/// A scene with an environment background followed by the image it shows,
+/// which takes the place of whatever the scene names as its map.
+#[post("/scene/{scene_size}/")]
+async fn scene_with_environment(
+    body: Bytes,
+    path: web::Path<usize>,
+    overrides: web::Query<RenderOverrides>,
+    state: web::Data<RwLock<AppState>>,
+) -> HttpResponse {
+    info!("Got scene with environment map");
+    let scene_size = path.into_inner();
+    if scene_size > body.len() {
+        return HttpResponse::BadRequest().body("scene size is larger than the upload");
+    }
+    submit_scene(
+        state,
+        body.slice(..scene_size),
+        Some(body.slice(scene_size..)),
+        overrides.into_inner(),
+    )
+    .await
+}
+
+async fn submit_scene(
+    state: web::Data<RwLock<AppState>>,
+    data: Bytes,
+    environment: Option<Bytes>,
+    overrides: RenderOverrides,
+) -> HttpResponse {
+    queue_job(&state, move || {
+        let mut uploaded = Scene::from_json(&data).map_err(|e| e.to_string())?;
+        overrides.apply(&mut uploaded.render);
+        uploaded.render.validate().map_err(|e| e.to_string())?;
+        let environment = match (&mut uploaded.background, environment) {
+            (Background::Environment { map, .. }, Some(data)) => {
+                EnvironmentMap::decode(&data)
+                    .map_err(|e| format!("failed to read environment map: {}", e))?;
+                *map = environment_hash(&data);
+                Some((map.clone(), data))
+            }
+            (_, Some(_)) => return Err("scene background isn't an environment map".to_owned()),
+            // Maps uploaded before can be reused by their hash.
+            (_, None) => None,
+        };
+        let world = uploaded.build_world().map_err(|e| e.to_string())?;
+        let camera = uploaded
+            .camera
+            .build(uploaded.render.width, uploaded.render.height);
+        Ok(NewJob {
+            render: uploaded.render,
+            world,
+            camera,
+            background: uploaded.background,
+            environment,
+            lights: uploaded.lights,
+        })
+    })
+    .await
+}
+
+#[post("/register")]
+async fn register(
+    req: web::Json<SlaveRegistration>,
+    state: web::Data<RwLock<AppState>>,
+) -> impl Responder {
+    let id = state.write().unwrap().slaves.register(req.into_inner());
     id.to_string()
 }
 
+#[post("/heartbeat/{id}")]
+async fn heartbeat(path: web::Path<Uuid>, state: web::Data<RwLock<AppState>>) -> HttpResponse {
+    let mut state = state.write().unwrap();
+    if state.slaves.heartbeat(path.into_inner()) {
+        HttpResponse::Ok().json(HeartbeatReply {
+            cancelled: state.cancelled.keys().copied().collect(),
+        })
+    } else {
+        HttpResponse::NotFound().body("unknown slave, register again")
+    }
+}
+
+/// Encodes `value` the way the client asked for in its `Accept` header.
+fn encoded<T: Serialize + ?Sized>(req: &HttpRequest, value: &T) -> HttpResponse {
+    let encoding = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(Encoding::from_accept)
+        .unwrap_or_default();
+    match encoding.encode(value) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(encoding.content_type())
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Claims the next tile for a slave. Jobs are served in the order they came
+/// in; 204 means there is nothing to do right now.
+#[post("/work/{slave}")]
+async fn work(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    state: web::Data<RwLock<AppState>>,
+) -> HttpResponse {
+    let slave = path.into_inner();
+    let mut state = state.write().unwrap();
+    let state = &mut *state;
+    // Asking for work is as good a sign of life as a heartbeat.
+    if !state.slaves.heartbeat(slave) {
+        return HttpResponse::NotFound().body("unknown slave, register again");
+    }
+    let claimed = state.jobs.iter_mut().find_map(|job| job.claim(slave));
+    state.save_changed();
+    match claimed {
+        Some(work) => {
+            info!("slave {} takes {} of {}", slave, work, work.render_meta.id);
+            encoded(&req, &work)
+        }
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+#[get("/worlds/{hash}")]
+async fn get_world(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<RwLock<AppState>>,
+) -> HttpResponse {
+    let world = state.read().unwrap().worlds.get(&*path).cloned();
+    match world {
+        Some(world) => encoded(&req, &*world),
+        None => HttpResponse::NotFound().body("No such world"),
+    }
+}
+
+/// Environment maps go out as the image file they were uploaded as.
+#[get("/environments/{hash}")]
+async fn get_environment(
+    path: web::Path<String>,
+    state: web::Data<RwLock<AppState>>,
+) -> HttpResponse {
+    let data = state.read().unwrap().environments.get(&*path).cloned();
+    match data {
+        Some(data) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(data),
+        None => HttpResponse::NotFound().body("No such environment map"),
+    }
+}
+
+#[get("/slaves")]
+async fn list_slaves(state: web::Data<RwLock<AppState>>) -> impl Responder {
+    web::Json(state.read().unwrap().slaves.status())
+}
+
 #[post("/result")]
-async fn result(req: web::Json<ImageSlice>, state: web::Data<RwLock<AppState>>) -> impl Responder {
+async fn result(
+    http_req: HttpRequest,
+    body: Bytes,
+    state: web::Data<RwLock<AppState>>,
+) -> HttpResponse {
+    let encoding = match http_req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(Encoding::from_content_type)
+        .unwrap_or(Ok(Encoding::Json))
+    {
+        Ok(encoding) => encoding,
+        Err(e) => return HttpResponse::UnsupportedMediaType().body(e.to_string()),
+    };
+    let req: ImageSlice = match encoding.decode(&body) {
+        Ok(slice) => slice,
+        Err(e @ WireError::TooLarge(_)) => {
+            return HttpResponse::PayloadTooLarge().body(e.to_string())
+        }
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
     info!(
-        "Got results from slave: id {} no {}",
-        req.id, req.division_no
+        "Got results from slave: id {} tile {} pass {}",
+        req.id, req.tile_no, req.pass
     );
     let id = req.id;
     let mut state = state.write().unwrap();
-    if let Some(idx) = state.jobs.iter().position(|job| job.render_meta.id == id) {
-        state.jobs[idx].result.push(req.into_inner());
-    } else {
+    let state = &mut *state;
+    let Some(job) = state.jobs.iter_mut().find(|job| job.render_meta.id == id) else {
         info!("result not saved. ID wrong? : {}", id);
+        return no_such_job();
+    };
+    if !job.add_pass(&req) {
+        // A late answer for a tile that was already re-dispatched, or one
+        // that doesn't fit the job.
+        info!(
+            "ignoring pass {} of tile {} of {}",
+            req.pass, req.tile_no, id
+        );
+        return json_error(
+            StatusCode::CONFLICT,
+            "pass not needed or doesn't fit the job",
+        );
+    }
+    if job.is_complete() {
+        info!("job {} done", id);
+        let radiance = job.radiance();
+        state.store(Change::Radiance(id, radiance));
+        state.save_changed();
+        state.prune_worlds();
+    } else {
+        state.store(Change::Pass(req));
+    }
+    HttpResponse::Ok().body("slice saved. thank you slave.")
+}
+
+fn json_error(status: StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "error": message }))
+}
+
+fn no_such_job() -> HttpResponse {
+    json_error(StatusCode::NOT_FOUND, "no such job")
+}
+
+#[get("/jobs/{id}")]
+async fn job_status(path: web::Path<Uuid>, state: web::Data<RwLock<AppState>>) -> HttpResponse {
+    let id = path.into_inner();
+    let state = state.read().unwrap();
+    match state.jobs.iter().find(|job| job.render_meta.id == id) {
+        Some(job) => HttpResponse::Ok().json(job.status()),
+        None => no_such_job(),
     }
-    "slice saved. thank you slave."
 }
 
-#[post("/poll")]
-async fn poll(req: String, state: web::Data<RwLock<AppState>>) -> Bytes {
-    info!("poll {}", req);
+/// Removes a job. Slaves still rendering parts of it hear about it with their
+/// next heartbeat and drop what they are doing.
+#[delete("/jobs/{id}")]
+async fn delete_job(path: web::Path<Uuid>, state: web::Data<RwLock<AppState>>) -> HttpResponse {
+    let id = path.into_inner();
     let mut state = state.write().unwrap();
-    Uuid::parse_str(&req)
-        .map(|id| {
-            if let Some(idx) = state.jobs.iter().position(|job| {
-                info!("{}", job.render_meta.id);
-                job.render_meta.id == id
-            }) {
-                let job = &mut state.jobs[idx];
-                if (0..job.render_meta.divisions)
-                    .all(|i| job.result.iter().find(|res| res.division_no == i).is_some())
-                {
-                    job.result.sort_by(|a, b| a.division_no.cmp(&b.division_no));
-                    let res: Vec<u8> = job
-                        .result
-                        .iter()
-                        .map(|a| a.image.clone())
-                        .flatten()
-                        .collect();
-                    let mut c = std::io::Cursor::new(Vec::new());
-                    let img: ImageBuffer<Rgb<u8>, _> =
-                        ImageBuffer::from_vec(job.render_meta.width, job.render_meta.height, res)
-                            .unwrap();
-                    img.write_to(&mut c, image::ImageOutputFormat::Jpeg(90))
-                        .unwrap();
-                    state.jobs.remove(idx);
-                    Bytes::from(c.into_inner())
-                } else {
-                    Bytes::from(format!(
-                        "Job not finished yet {}/{}",
-                        (0..job.render_meta.divisions)
-                            .filter(|i| job
-                                .result
-                                .iter()
-                                .find(|res| res.division_no == *i)
-                                .is_some())
-                            .count(),
-                        job.render_meta.divisions
-                    ))
-                }
-            } else {
-                Bytes::from_static(b"No such job")
+    let Some(idx) = state.jobs.iter().position(|job| job.render_meta.id == id) else {
+        return no_such_job();
+    };
+    let job = state.jobs.remove(idx);
+    info!("job {} deleted", id);
+    state.store(Change::RemoveJob(id));
+    if job.state() == JobState::Rendering {
+        state.cancelled.insert(id, Instant::now());
+    }
+    state.prune_worlds();
+    HttpResponse::NoContent().finish()
+}
+
+#[derive(Deserialize)]
+struct ImageOptions {
+    /// Return the image as rendered so far instead of refusing until the job
+    /// is done.
+    #[serde(default)]
+    preview: bool,
+    #[serde(default)]
+    format: OutputFormat,
+    /// Only used for LDR formats, like `exposure` and `gamma`.
+    #[serde(default)]
+    tonemap: Tonemap,
+    #[serde(default)]
+    exposure: f32,
+    /// Defaults to the job's gamma.
+    gamma: Option<f32>,
+}
+
+#[get("/jobs/{id}/image")]
+async fn job_image(
+    path: web::Path<Uuid>,
+    options: web::Query<ImageOptions>,
+    state: web::Data<RwLock<AppState>>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let (width, height, gamma, radiance) = {
+        let state = state.read().unwrap();
+        let Some(job) = state.jobs.iter().find(|job| job.render_meta.id == id) else {
+            return no_such_job();
+        };
+        match job.state() {
+            JobState::Done => {}
+            JobState::Failed => {
+                return json_error(StatusCode::CONFLICT, job.error().unwrap_or("job failed"))
             }
-        })
-        .unwrap_or(Bytes::from_static(b"Invalid Uuid"))
+            _ if options.preview => {}
+            _ => return json_error(StatusCode::CONFLICT, "job not finished yet"),
+        }
+        (
+            job.render_meta.width,
+            job.render_meta.height,
+            job.render_meta.gamma,
+            job.radiance(),
+        )
+    };
+    let ldr = LdrSettings {
+        tonemap: options.tonemap,
+        exposure: options.exposure,
+        gamma: options.gamma.unwrap_or(gamma),
+    };
+    if ldr.gamma.is_nan() || ldr.gamma <= 0f32 {
+        return json_error(StatusCode::BAD_REQUEST, "gamma must be positive");
+    }
+    let format = options.format;
+    let encoded = web::block(move || output::encode(width, height, radiance, format, &ldr)).await;
+    match encoded {
+        Ok(Ok(image)) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(image),
+        Ok(Err(e)) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
 }
 
 #[actix_web::main]
 async fn main() {
     pretty_env_logger::init();
-    let state = web::Data::new(RwLock::new(AppState { jobs: Vec::new() }));
+    let store = JobStore::open(job_store_dir()).unwrap();
+    let stored = store.load();
+    let mut state = AppState {
+        jobs: stored.jobs,
+        slaves: SlaveTable::default(),
+        worlds: stored.worlds,
+        environments: stored.environments,
+        cancelled: HashMap::new(),
+        store: store.spawn_writer(),
+    };
+    state.save_changed();
+    let state = web::Data::new(RwLock::new(state));
+    let watchdog_state = state.clone();
+    let tile_timeout = tile_timeout();
+    let job_retention = job_retention();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut state = watchdog_state.write().unwrap();
+            let state = &mut *state;
+            state.slaves.reap(SLAVE_TIMEOUT);
+            state
+                .cancelled
+                .retain(|_, cancelled_at| cancelled_at.elapsed() < tile_timeout);
+            for job in &mut state.jobs {
+                let released = job.release_overdue(tile_timeout, |id| state.slaves.contains(id));
+                if !released.is_empty() {
+                    info!(
+                        "taking back tiles {:?} of job {}",
+                        released, job.render_meta.id
+                    );
+                    if let Some(error) = job.error() {
+                        error!("job {} failed: {}", job.render_meta.id, error);
+                    }
+                }
+            }
+            let mut expired_jobs = Vec::new();
+            state.jobs.retain(|job| {
+                let expired = job
+                    .finished_at()
+                    .and_then(|finished_at| finished_at.elapsed().ok())
+                    .is_some_and(|age| age > job_retention);
+                if expired {
+                    info!("job {} expired", job.render_meta.id);
+                    expired_jobs.push(job.render_meta.id);
+                }
+                !expired
+            });
+            for id in expired_jobs {
+                state.store(Change::RemoveJob(id));
+            }
+            state.save_changed();
+            state.prune_worlds();
+        }
+    });
 
     HttpServer::new(move || {
         App::new()
             .service(index)
-            .service(poll)
+            .service(scene)
+            .service(scene_with_environment)
+            .service(job_status)
+            .service(job_image)
+            .service(delete_job)
             .service(result)
+            .service(register)
+            .service(heartbeat)
+            .service(work)
+            .service(get_world)
+            .service(get_environment)
+            .service(list_slaves)
             .app_data(state.clone())
             .app_data(web::PayloadConfig::new(500_000_000))
-            .app_data(web::JsonConfig::default().limit(500_000_000))
+            .app_data(web::PathConfig::default().error_handler(|e, _| {
+                let response = json_error(StatusCode::BAD_REQUEST, &e.to_string());
+                InternalError::from_response(e, response).into()
+            }))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| {
+                let response = json_error(StatusCode::BAD_REQUEST, &e.to_string());
+                InternalError::from_response(e, response).into()
+            }))
     })
     .bind(("0.0.0.0", 8080))
     .unwrap()
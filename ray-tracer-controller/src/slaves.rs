@@ -0,0 +1,89 @@
+use log::info;
+use ray_tracer_interface::SlaveRegistration;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+pub struct Slave {
+    pub address: String,
+    pub cores: u32,
+    last_seen: Instant,
+}
+
+#[derive(Serialize)]
+pub struct SlaveStatus {
+    id: Uuid,
+    address: String,
+    cores: u32,
+    last_seen_secs: f32,
+}
+
+#[derive(Default)]
+pub struct SlaveTable(HashMap<Uuid, Slave>);
+
+impl SlaveTable {
+    pub fn register(&mut self, registration: SlaveRegistration) -> Uuid {
+        // A slave that restarted registers again from the same address.
+        self.0
+            .retain(|_, slave| slave.address != registration.address);
+        let id = Uuid::new_v4();
+        info!(
+            "slave {} registered at {} with {} cores",
+            id, registration.address, registration.cores
+        );
+        self.0.insert(
+            id,
+            Slave {
+                address: registration.address,
+                cores: registration.cores.max(1),
+                last_seen: Instant::now(),
+            },
+        );
+        id
+    }
+
+    /// Returns false for slaves we don't know, which should register again.
+    pub fn heartbeat(&mut self, id: Uuid) -> bool {
+        if let Some(slave) = self.0.get_mut(&id) {
+            slave.last_seen = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn reap(&mut self, timeout: Duration) -> Vec<Uuid> {
+        let dead: Vec<Uuid> = self
+            .0
+            .iter()
+            .filter(|(_, slave)| slave.last_seen.elapsed() > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &dead {
+            if let Some(slave) = self.0.remove(id) {
+                info!(
+                    "dropping slave {} at {}: heartbeat timed out",
+                    id, slave.address
+                );
+            }
+        }
+        dead
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.0.contains_key(id)
+    }
+
+    pub fn status(&self) -> Vec<SlaveStatus> {
+        self.0
+            .iter()
+            .map(|(id, slave)| SlaveStatus {
+                id: *id,
+                address: slave.address.clone(),
+                cores: slave.cores,
+                last_seen_secs: slave.last_seen.elapsed().as_secs_f32(),
+            })
+            .collect()
+    }
+}
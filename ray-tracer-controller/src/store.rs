@@ -0,0 +1,428 @@
+//! Keeps jobs on disk so results can be fetched after a restart and jobs that
+//! were rendering pick up where they left off. Every job gets a directory
+//! holding its record, the passes accepted so far and, once done, the final
+//! radiance, which replaces the passes. Worlds and environment maps are kept
+//! next to the jobs by hash for as long as some job still has to render them.
+use crate::jobs::{Job, JobRecord};
+use actix_web::web::Bytes;
+use crossbeam_channel::Sender;
+use displaydoc::Display;
+use log::{error, info, warn};
+use ray_tracer_interface::{
+    render::WorldData,
+    wire::{Encoding, WireError},
+    ImageSlice,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Debug, Display)]
+pub enum StoreError {
+    /// job store i/o failed: {0}
+    Io(io::Error),
+    /// job store file is corrupt: {0}
+    Encoding(WireError),
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<WireError> for StoreError {
+    fn from(e: WireError) -> Self {
+        Self::Encoding(e)
+    }
+}
+
+const RECORD: &str = "job.bin";
+const RADIANCE: &str = "radiance.bin";
+const PASSES: &str = "passes";
+const WORLDS: &str = "worlds";
+const ENVIRONMENTS: &str = "environments";
+
+/// Everything read back from a store.
+pub struct Stored {
+    pub jobs: Vec<Job>,
+    pub worlds: HashMap<String, Arc<WorldData>>,
+    pub environments: HashMap<String, Bytes>,
+}
+
+/// A change to a store, carried out by the thread `JobStore::spawn_writer`
+/// starts.
+pub enum Change {
+    Job(Box<JobRecord>),
+    Pass(ImageSlice),
+    Radiance(Uuid, Vec<f32>),
+    RemoveJob(Uuid),
+    World(String, Arc<WorldData>),
+    RemoveWorld(String),
+    Environment(String, Bytes),
+    RemoveEnvironment(String),
+}
+
+#[derive(Clone)]
+pub struct JobStore {
+    dir: PathBuf,
+}
+
+impl JobStore {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, StoreError> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(WORLDS))?;
+        fs::create_dir_all(dir.join(ENVIRONMENTS))?;
+        Ok(Self { dir })
+    }
+
+    fn job_dir(&self, id: Uuid) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn world_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(WORLDS).join(format!("{}.bin", hash))
+    }
+
+    fn environment_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(ENVIRONMENTS).join(hash)
+    }
+
+    /// Starts a thread making the changes sent to it one after the other, in
+    /// the order they were sent in. Changes can then be decided on while
+    /// holding the state without everyone else waiting for the disk.
+    pub fn spawn_writer(&self) -> Sender<Change> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let store = self.clone();
+        std::thread::spawn(move || {
+            for change in receiver {
+                if let Err(e) = store.apply(change) {
+                    error!("{}", e);
+                }
+            }
+        });
+        sender
+    }
+
+    fn apply(&self, change: Change) -> Result<(), StoreError> {
+        match change {
+            Change::Job(record) => self.save_job(&record),
+            Change::Pass(slice) => self.save_pass(&slice),
+            Change::Radiance(id, radiance) => self.save_radiance(id, &radiance),
+            Change::RemoveJob(id) => self.remove_job(id),
+            Change::World(hash, world) => self.save_world(&hash, &world),
+            Change::RemoveWorld(hash) => self.remove_world(&hash),
+            Change::Environment(hash, data) => self.save_environment(&hash, &data),
+            Change::RemoveEnvironment(hash) => self.remove_environment(&hash),
+        }
+    }
+
+    pub fn save_job(&self, record: &JobRecord) -> Result<(), StoreError> {
+        let dir = self.job_dir(record.render_meta.id);
+        fs::create_dir_all(&dir)?;
+        write(&dir.join(RECORD), record)
+    }
+
+    pub fn save_pass(&self, slice: &ImageSlice) -> Result<(), StoreError> {
+        let dir = self.job_dir(slice.id).join(PASSES);
+        fs::create_dir_all(&dir)?;
+        write(
+            &dir.join(format!("{}-{}.bin", slice.pass, slice.tile_no)),
+            slice,
+        )
+    }
+
+    /// Stores the final image of a job, after which its passes aren't needed.
+    pub fn save_radiance(&self, id: Uuid, radiance: &[f32]) -> Result<(), StoreError> {
+        let dir = self.job_dir(id);
+        write(&dir.join(RADIANCE), radiance)?;
+        remove(fs::remove_dir_all(dir.join(PASSES)))
+    }
+
+    pub fn remove_job(&self, id: Uuid) -> Result<(), StoreError> {
+        remove(fs::remove_dir_all(self.job_dir(id)))
+    }
+
+    pub fn save_world(&self, hash: &str, world: &WorldData) -> Result<(), StoreError> {
+        let path = self.world_path(hash);
+        if path.exists() {
+            return Ok(());
+        }
+        write(&path, world)
+    }
+
+    pub fn remove_world(&self, hash: &str) -> Result<(), StoreError> {
+        remove(fs::remove_file(self.world_path(hash)))
+    }
+
+    /// Environment maps are kept as the image file they were uploaded as.
+    pub fn save_environment(&self, hash: &str, data: &[u8]) -> Result<(), StoreError> {
+        let path = self.environment_path(hash);
+        if path.exists() {
+            return Ok(());
+        }
+        write_bytes(&path, data)
+    }
+
+    pub fn remove_environment(&self, hash: &str) -> Result<(), StoreError> {
+        remove(fs::remove_file(self.environment_path(hash)))
+    }
+
+    /// Reads back every job, oldest first, along with the worlds and
+    /// environment maps of those still rendering. Jobs that can't be read are
+    /// skipped, and those whose world or environment map can't be are marked
+    /// failed.
+    pub fn load(&self) -> Stored {
+        let mut stored = Stored {
+            jobs: Vec::new(),
+            worlds: HashMap::new(),
+            environments: HashMap::new(),
+        };
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return stored;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.join(RECORD).exists() {
+                continue;
+            }
+            match self.load_job(&path, &mut stored) {
+                Ok(job) => stored.jobs.push(job),
+                Err(e) => warn!("skipping stored job {}: {}", path.display(), e),
+            }
+        }
+        stored.jobs.sort_by_key(|job| job.created_at());
+        info!(
+            "loaded {} jobs from {}",
+            stored.jobs.len(),
+            self.dir.display()
+        );
+        stored
+    }
+
+    fn load_job(&self, dir: &Path, stored: &mut Stored) -> Result<Job, StoreError> {
+        let record: JobRecord = read(&dir.join(RECORD))?;
+        let mut job = Job::restore(record);
+        if dir.join(RADIANCE).exists() {
+            let radiance: Vec<f32> = read(&dir.join(RADIANCE))?;
+            job.restore_radiance(&radiance);
+            return Ok(job);
+        }
+        let mut passes: Vec<ImageSlice> = Vec::new();
+        if dir.join(PASSES).exists() {
+            for entry in fs::read_dir(dir.join(PASSES))? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|extension| extension == "bin") {
+                    passes.push(read(&path)?);
+                }
+            }
+        }
+        // Passes of a tile have to go in in order.
+        passes.sort_by_key(|slice| (slice.pass, slice.tile_no));
+        for slice in &passes {
+            job.add_pass(slice);
+        }
+        if job.is_complete() {
+            // We went down between the last pass and saving the image.
+            self.save_radiance(job.render_meta.id, &job.radiance())?;
+            return Ok(job);
+        }
+        if job.error().is_some() {
+            return Ok(job);
+        }
+        if let Err(e) = self.load_scene(&job, stored) {
+            warn!("failing stored job {}: {}", dir.display(), e);
+            job.fail(format!("its scene couldn't be read back: {}", e));
+            self.save_job(&job.record())?;
+            job.take_changed();
+        }
+        Ok(job)
+    }
+
+    /// Reads the world and environment map `job` renders into `stored`,
+    /// unless they are there already.
+    fn load_scene(&self, job: &Job, stored: &mut Stored) -> Result<(), StoreError> {
+        if !stored.worlds.contains_key(&job.scene.world) {
+            let world: WorldData = read(&self.world_path(&job.scene.world))?;
+            stored
+                .worlds
+                .insert(job.scene.world.clone(), Arc::new(world));
+        }
+        if let Some(hash) = job.scene.background.environment_map() {
+            if !stored.environments.contains_key(hash) {
+                let data = fs::read(self.environment_path(hash))?;
+                stored.environments.insert(hash.to_owned(), data.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<(), StoreError> {
+    write_bytes(path, &Encoding::Bincode.encode(value)?)
+}
+
+/// Writes through a temporary file so a crash never leaves half a file behind.
+fn write_bytes(path: &Path, data: &[u8]) -> Result<(), StoreError> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Treats removing something that is already gone as success.
+fn remove(result: io::Result<()>) -> Result<(), StoreError> {
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn read<T: DeserializeOwned>(path: &Path) -> Result<T, StoreError> {
+    Ok(Encoding::Bincode.decode(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jobs::{JobScene, JobState};
+    use ray_tracer_interface::{camera::CameraSettings, scene::Background, RenderMeta};
+    use std::time::Duration;
+
+    /// A job of two 2x2 tiles, along with its world.
+    fn job(progressive: bool) -> (Job, WorldData) {
+        let world = WorldData::build(Vec::new());
+        let job = Job::new(
+            RenderMeta {
+                height: 2,
+                width: 4,
+                tile_size: 2,
+                samples_per_pixel: 5,
+                max_depth: 4,
+                seed: 1,
+                gamma: 2.2,
+                progressive,
+                id: Uuid::new_v4(),
+            },
+            JobScene {
+                world: world.hash(),
+                camera: CameraSettings::default().build(4, 2),
+                background: Background::default(),
+                lights: Vec::new(),
+            },
+        );
+        (job, world)
+    }
+
+    /// Renders the next pass of a tile, stores it and returns the tile.
+    fn render_pass(store: &JobStore, job: &mut Job) -> u32 {
+        let slave = Uuid::new_v4();
+        let work = job.claim(slave).unwrap();
+        let slice = ImageSlice {
+            tile_no: work.tile_no,
+            pass: work.pass,
+            samples: work.samples,
+            image: (0..12).map(|i| (i + work.pass) as f32).collect(),
+            id: work.render_meta.id,
+            slave,
+        };
+        assert!(job.add_pass(&slice));
+        store.save_pass(&slice).unwrap();
+        work.tile_no
+    }
+
+    fn load_one(store: &JobStore) -> (Job, Stored) {
+        let mut stored = store.load();
+        assert_eq!(stored.jobs.len(), 1);
+        (stored.jobs.remove(0), stored)
+    }
+
+    #[test]
+    fn test_partial_job_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JobStore::open(dir.path()).unwrap();
+        let (mut job, world) = job(true);
+        store.save_world(&world.hash(), &world).unwrap();
+        for _ in 0..3 {
+            render_pass(&store, &mut job);
+        }
+        store.save_job(&job.record()).unwrap();
+
+        let (loaded, stored) = load_one(&store);
+        assert_eq!(loaded.render_meta.id, job.render_meta.id);
+        assert_eq!(loaded.state(), JobState::Rendering);
+        assert_eq!(loaded.completed(), job.completed());
+        assert_eq!(loaded.radiance(), job.radiance());
+        assert!(stored.worlds.contains_key(&job.scene.world));
+    }
+
+    #[test]
+    fn test_job_done_before_its_radiance_was_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JobStore::open(dir.path()).unwrap();
+        let (mut job, world) = job(false);
+        store.save_world(&world.hash(), &world).unwrap();
+        render_pass(&store, &mut job);
+        render_pass(&store, &mut job);
+        assert_eq!(job.state(), JobState::Done);
+        store.save_job(&job.record()).unwrap();
+
+        let (loaded, stored) = load_one(&store);
+        assert_eq!(loaded.state(), JobState::Done);
+        assert_eq!(loaded.radiance(), job.radiance());
+        assert!(stored.worlds.is_empty());
+        let job_dir = store.job_dir(job.render_meta.id);
+        assert!(job_dir.join(RADIANCE).exists());
+        assert!(!job_dir.join(PASSES).exists());
+
+        // Next time it comes from the radiance alone.
+        let (loaded, _) = load_one(&store);
+        assert_eq!(loaded.state(), JobState::Done);
+        assert_eq!(loaded.radiance(), job.radiance());
+    }
+
+    #[test]
+    fn test_failed_job_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JobStore::open(dir.path()).unwrap();
+        // Its world is gone, which a failed job doesn't need.
+        let (mut job, _) = job(true);
+        render_pass(&store, &mut job);
+        while job.error().is_none() {
+            job.claim(Uuid::new_v4());
+            job.release_overdue(Duration::from_secs(60), |_| false);
+        }
+        store.save_job(&job.record()).unwrap();
+
+        let (loaded, stored) = load_one(&store);
+        assert_eq!(loaded.state(), JobState::Failed);
+        assert_eq!(loaded.error(), job.error());
+        assert_eq!(loaded.finished_at(), job.finished_at());
+        assert!(stored.worlds.is_empty());
+    }
+
+    #[test]
+    fn test_job_without_its_world_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JobStore::open(dir.path()).unwrap();
+        let (mut job, _) = job(true);
+        render_pass(&store, &mut job);
+        store.save_job(&job.record()).unwrap();
+
+        let (loaded, stored) = load_one(&store);
+        assert_eq!(loaded.state(), JobState::Failed);
+        assert!(loaded.error().unwrap().contains("scene"));
+        assert!(stored.worlds.is_empty());
+
+        // The failure was saved, so the world isn't looked for again.
+        let (reloaded, _) = load_one(&store);
+        assert_eq!(reloaded.error(), loaded.error());
+        assert_eq!(reloaded.finished_at(), loaded.finished_at());
+    }
+}
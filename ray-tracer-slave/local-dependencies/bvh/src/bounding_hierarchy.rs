@@ -183,7 +183,7 @@ pub trait BoundingHierarchy {
     /// [`BoundingHierarchy`]: trait.BoundingHierarchy.html
     /// [`AABB`]: ../aabb/struct.AABB.html
     ///
-    fn traverse<'a, Shape: BHShape>(&'a self, ray: &Ray, shapes: &'a [Shape]) -> Vec<&Shape>;
+    fn traverse<'a, Shape: BHShape>(&'a self, ray: &Ray, shapes: &'a [Shape]) -> Vec<&'a Shape>;
 
     /// Prints the [`BoundingHierarchy`] in a tree-like visualization.
     ///
@@ -197,7 +197,7 @@ impl<T: BoundingHierarchy> BoundingHierarchy for Box<T> {
         Box::new(T::build(shapes))
     }
 
-    fn traverse<'a, Shape: BHShape>(&'a self, ray: &Ray, shapes: &'a [Shape]) -> Vec<&Shape> {
+    fn traverse<'a, Shape: BHShape>(&'a self, ray: &Ray, shapes: &'a [Shape]) -> Vec<&'a Shape> {
         T::traverse(self, ray, shapes)
     }
 }
@@ -0,0 +1,552 @@
+//! This module defines a binned SAH builder for the [`BVH`], which builds large subtrees
+//! in parallel and can put several shapes into one leaf.
+//! Based on [`https://www.sci.utah.edu/~wald/Publications/2007/ParallelBVHBuild/fastbuild.pdf`]
+//!
+//! [`BVH`]: struct.BVH.html
+//!
+
+use crate::aabb::AABB;
+use crate::bounding_hierarchy::BHShape;
+use crate::bvh::*;
+use crate::Point3;
+use crate::EPSILON;
+
+use rayon::prelude::*;
+
+/// The number of bins the centroids are sorted into along the split axis.
+const NUM_BINS: usize = 16;
+
+/// The cost of traversing a node, relative to the cost of intersecting a shape. Entering
+/// a node means testing the `AABB`s of both children, which is about as expensive as
+/// testing a shape.
+const TRAVERSAL_COST: f32 = 1.0;
+
+/// Ranges with fewer shapes than this are binned and split on the current thread.
+const PARALLEL_THRESHOLD: usize = 4096;
+
+/// A shape's index along with what the build needs to know about it.
+#[derive(Copy, Clone)]
+struct BuildShape {
+    index: usize,
+    aabb: AABB,
+    center: Point3,
+}
+
+/// The shapes that fall into a bin, or a range of bins. Unlike a [`Bucket`], it also
+/// keeps the bounds of their centers, so that a split doesn't have to go over the shapes
+/// again to know them.
+///
+/// [`Bucket`]: ../utils/struct.Bucket.html
+///
+#[derive(Copy, Clone)]
+struct Bin {
+    size: usize,
+    aabb: AABB,
+    centers: AABB,
+}
+
+impl Bin {
+    fn empty() -> Bin {
+        Bin {
+            size: 0,
+            aabb: AABB::empty(),
+            centers: AABB::empty(),
+        }
+    }
+
+    fn add(&mut self, shape: &BuildShape) {
+        self.size += 1;
+        self.aabb.join_mut(&shape.aabb);
+        self.centers.grow_mut(&shape.center);
+    }
+
+    fn join(&self, other: &Bin) -> Bin {
+        Bin {
+            size: self.size + other.size,
+            aabb: self.aabb.join(&other.aabb),
+            centers: self.centers.join(&other.centers),
+        }
+    }
+
+    /// The cost of testing every shape in the bin, weighted by the surface area of its
+    /// `AABB`.
+    fn cost(&self) -> f32 {
+        self.size as f32 * self.aabb.surface_area()
+    }
+}
+
+/// Puts all `shapes` into a single [`Bin`].
+///
+/// [`Bin`]: struct.Bin.html
+///
+fn bin_all(shapes: &[BuildShape]) -> Bin {
+    fn bin_serial(shapes: &[BuildShape]) -> Bin {
+        let mut bin = Bin::empty();
+        for shape in shapes {
+            bin.add(shape);
+        }
+        bin
+    }
+
+    if shapes.len() < PARALLEL_THRESHOLD {
+        return bin_serial(shapes);
+    }
+    shapes
+        .par_chunks(PARALLEL_THRESHOLD)
+        .map(bin_serial)
+        .reduce(Bin::empty, |a, b| a.join(&b))
+}
+
+/// Sorts `shapes` into bins by `bin_of`.
+fn bin<F: Fn(&BuildShape) -> usize + Sync>(shapes: &[BuildShape], bin_of: &F) -> [Bin; NUM_BINS] {
+    let bin_serial = |shapes: &[BuildShape]| {
+        let mut bins = [Bin::empty(); NUM_BINS];
+        for shape in shapes {
+            bins[bin_of(shape)].add(shape);
+        }
+        bins
+    };
+
+    if shapes.len() < PARALLEL_THRESHOLD {
+        return bin_serial(shapes);
+    }
+    shapes
+        .par_chunks(PARALLEL_THRESHOLD)
+        .map(bin_serial)
+        .reduce(
+            || [Bin::empty(); NUM_BINS],
+            |mut a, b| {
+                for (a, b) in a.iter_mut().zip(b.iter()) {
+                    *a = a.join(b);
+                }
+                a
+            },
+        )
+}
+
+/// Finds where to split `shapes`, which all fall into `all`, and returns the [`Bin`]s of
+/// both sides. Returns `None` if the shapes should rather stay together in a leaf. Moves
+/// the shapes of the left side to the front.
+///
+/// [`Bin`]: struct.Bin.html
+///
+fn split(shapes: &mut [BuildShape], all: &Bin, max_leaf_size: usize) -> Option<(Bin, Bin)> {
+    let count = shapes.len();
+    let split_axis = all.centers.largest_axis();
+    let split_axis_min = all.centers.min[split_axis];
+    let split_axis_size = all.centers.max[split_axis] - split_axis_min;
+
+    if split_axis_size < EPSILON {
+        // The shapes lie too close together to be split in a sensible way. Unless they fit
+        // into a leaf, we just split the list of shapes in half.
+        if count <= max_leaf_size {
+            return None;
+        }
+        let (shapes_l, shapes_r) = shapes.split_at(count / 2);
+        return Some((bin_all(shapes_l), bin_all(shapes_r)));
+    }
+
+    // Small ranges don't need all bins, and sweeping them would dominate.
+    let num_bins = count.min(NUM_BINS);
+    let scale = (num_bins as f32 - 0.01) / split_axis_size;
+    let bin_of = |shape: &BuildShape| {
+        (((shape.center[split_axis] - split_axis_min) * scale) as usize).min(num_bins - 1)
+    };
+    let bins = bin(shapes, &bin_of);
+
+    // Sweep from the right to get every right side, then from the left to find the
+    // cheapest split.
+    let mut right = [Bin::empty(); NUM_BINS];
+    for i in (1..num_bins).rev() {
+        right[i - 1] = bins[i].join(&right[i]);
+    }
+    let mut left = Bin::empty();
+    let mut best = None;
+    let mut min_cost = f32::INFINITY;
+    for i in 0..(num_bins - 1) {
+        left = left.join(&bins[i]);
+        if left.size == 0 || right[i].size == 0 {
+            continue;
+        }
+        let cost = TRAVERSAL_COST + (left.cost() + right[i].cost()) / all.aabb.surface_area();
+        if cost < min_cost {
+            best = Some((i, left, right[i]));
+            min_cost = cost;
+        }
+    }
+    let (split_bin, left, right) = best?;
+
+    // Testing every shape of a leaf costs one per shape.
+    if count <= max_leaf_size && min_cost >= count as f32 {
+        return None;
+    }
+
+    let mut split = 0;
+    for i in 0..count {
+        if bin_of(&shapes[i]) <= split_bin {
+            shapes.swap(i, split);
+            split += 1;
+        }
+    }
+    debug_assert_eq!(split, left.size);
+    Some((left, right))
+}
+
+/// Builds the subtree for `shapes`, which all fall into `all`, and appends it to `nodes`.
+/// The first of the shapes is shape `offset` in the final order. Reorders `shapes` so that
+/// each leaf's shapes lie next to each other. Returns the index of the subtree's root.
+#[allow(clippy::too_many_arguments)]
+fn build_range(
+    shapes: &mut [BuildShape],
+    offset: usize,
+    all: &Bin,
+    max_leaf_size: usize,
+    nodes: &mut Vec<BVHNode>,
+    parent_index: usize,
+    depth: u32,
+) -> usize {
+    let node_index = nodes.len();
+    let count = shapes.len();
+    let (bin_l, bin_r) = match split(shapes, all, max_leaf_size) {
+        Some(bins) if count > 1 => bins,
+        _ => {
+            nodes.push(BVHNode::Leaf {
+                parent_index,
+                depth,
+                shape_index: offset,
+                shape_count: count,
+            });
+            return node_index;
+        }
+    };
+
+    nodes.push(BVHNode::create_dummy());
+    let (shapes_l, shapes_r) = shapes.split_at_mut(bin_l.size);
+    let offset_r = offset + bin_l.size;
+    let (child_l_index, child_r_index) = if count < PARALLEL_THRESHOLD {
+        let child_l_index = build_range(
+            shapes_l,
+            offset,
+            &bin_l,
+            max_leaf_size,
+            nodes,
+            node_index,
+            depth + 1,
+        );
+        let child_r_index = build_range(
+            shapes_r,
+            offset_r,
+            &bin_r,
+            max_leaf_size,
+            nodes,
+            node_index,
+            depth + 1,
+        );
+        (child_l_index, child_r_index)
+    } else {
+        // Each side gets nodes of its own, which are moved behind this node afterwards.
+        let build_side = |shapes: &mut [BuildShape], offset: usize, bin: &Bin| {
+            let mut nodes = Vec::with_capacity(shapes.len() * 2);
+            build_range(shapes, offset, bin, max_leaf_size, &mut nodes, 0, depth + 1);
+            nodes
+        };
+        let (nodes_l, nodes_r) = rayon::join(
+            || build_side(shapes_l, offset, &bin_l),
+            || build_side(shapes_r, offset_r, &bin_r),
+        );
+        (
+            append_subtree(nodes, nodes_l, node_index),
+            append_subtree(nodes, nodes_r, node_index),
+        )
+    };
+    nodes[node_index] = BVHNode::Node {
+        parent_index,
+        depth,
+        child_l_aabb: bin_l.aabb,
+        child_l_index,
+        child_r_aabb: bin_r.aabb,
+        child_r_index,
+    };
+    node_index
+}
+
+/// Appends the nodes of a subtree that was built on its own to `nodes`, below the node at
+/// `parent_index`. Returns the index of the subtree's root.
+fn append_subtree(nodes: &mut Vec<BVHNode>, subtree: Vec<BVHNode>, parent_index: usize) -> usize {
+    let root_index = nodes.len();
+    nodes.extend(subtree.into_iter().enumerate().map(|(index, mut node)| {
+        *node.parent_mut() = if index == 0 {
+            parent_index
+        } else {
+            node.parent() + root_index
+        };
+        if let BVHNode::Node {
+            ref mut child_l_index,
+            ref mut child_r_index,
+            ..
+        } = node
+        {
+            *child_l_index += root_index;
+            *child_r_index += root_index;
+        }
+        node
+    }));
+    root_index
+}
+
+/// Reorders `shapes` in place so that `shapes[i]` becomes what was `shapes[order[i]]`.
+fn permute<Shape>(shapes: &mut [Shape], order: &[usize]) {
+    let mut done = vec![false; shapes.len()];
+    for start in 0..shapes.len() {
+        if done[start] {
+            continue;
+        }
+        // Follow the cycle through `start`, pulling each shape into place.
+        let mut i = start;
+        loop {
+            done[i] = true;
+            let j = order[i];
+            if j == start {
+                break;
+            }
+            shapes.swap(i, j);
+            i = j;
+        }
+    }
+}
+
+impl BVH {
+    /// Creates a new [`BVH`] from the `shapes` slice using binned SAH partitioning,
+    /// building large subtrees in parallel. Leaves hold up to `max_leaf_size` shapes,
+    /// where that is cheaper than splitting them further.
+    ///
+    /// Unlike [`build`], this reorders `shapes` so that the shapes of each leaf lie
+    /// next to each other.
+    ///
+    /// [`BVH`]: struct.BVH.html
+    /// [`build`]: struct.BVH.html#method.build
+    ///
+    pub fn build_binned<Shape: BHShape + Sync>(shapes: &mut [Shape], max_leaf_size: usize) -> BVH {
+        if shapes.is_empty() {
+            return BVH { nodes: Vec::new() };
+        }
+        let mut build_shapes = shapes
+            .par_iter()
+            .enumerate()
+            .map(|(index, shape)| {
+                let aabb = shape.aabb();
+                BuildShape {
+                    index,
+                    aabb,
+                    center: aabb.center(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let all = bin_all(&build_shapes);
+        let mut nodes = Vec::with_capacity(shapes.len() * 2);
+        build_range(
+            &mut build_shapes,
+            0,
+            &all,
+            max_leaf_size.max(1),
+            &mut nodes,
+            0,
+            0,
+        );
+
+        let order = build_shapes
+            .iter()
+            .map(|shape| shape.index)
+            .collect::<Vec<_>>();
+        permute(shapes, &order);
+
+        // Let the shapes know the index of the leaf that holds them.
+        for (node_index, node) in nodes.iter().enumerate() {
+            for shape_index in node.shape_indices() {
+                shapes[shape_index].set_bh_node_index(node_index);
+            }
+        }
+        BVH { nodes }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aabb::{Bounded, AABB};
+    use crate::bounding_hierarchy::{BHShape, BoundingHierarchy};
+    use crate::bvh::BVH;
+    use crate::ray::Ray;
+    use crate::testbase::{generate_aligned_boxes, next_point3, UnitBox};
+    use crate::Point3;
+
+    /// Creates `n` `UnitBox`es at deterministic random positions.
+    fn random_boxes(n: usize) -> Vec<UnitBox> {
+        let bounds = AABB::with_bounds(
+            Point3::new(-100.0, -100.0, -100.0),
+            Point3::new(100.0, 100.0, 100.0),
+        );
+        let mut seed = 0;
+        (0..n)
+            .map(|id| UnitBox::new(id as i32, next_point3(&mut seed, &bounds)))
+            .collect()
+    }
+
+    #[test]
+    /// Every shape ends up in exactly one leaf, which knows about it and is no larger than
+    /// allowed, and the tree is consistent and tight.
+    fn test_build_binned() {
+        for (mut shapes, max_leaf_size) in [
+            (generate_aligned_boxes(), 1),
+            (generate_aligned_boxes(), 4),
+            (random_boxes(10_000), 1),
+            (random_boxes(10_000), 8),
+        ] {
+            let count = shapes.len();
+            let mut expected_ids: Vec<_> = shapes.iter().map(|shape| shape.id).collect();
+            let bvh = BVH::build_binned(&mut shapes, max_leaf_size);
+            bvh.assert_consistent(&shapes);
+            bvh.assert_tight();
+
+            let mut seen = vec![0; count];
+            for (node_index, node) in bvh.nodes.iter().enumerate() {
+                assert!(node.shape_indices().len() <= max_leaf_size);
+                for shape_index in node.shape_indices() {
+                    seen[shape_index] += 1;
+                    assert_eq!(shapes[shape_index].bh_node_index(), node_index);
+                }
+            }
+            assert!(seen.iter().all(|&n| n == 1));
+
+            // The shapes are only reordered.
+            let mut ids: Vec<_> = shapes.iter().map(|shape| shape.id).collect();
+            ids.sort_unstable();
+            expected_ids.sort_unstable();
+            assert_eq!(ids, expected_ids);
+        }
+    }
+
+    #[test]
+    /// Traversing finds the same shapes as testing every one of them, however the tree is
+    /// walked, and `closest_hit` agrees with a tree from `BVH::build`.
+    fn test_traverse_binned() {
+        let mut shapes = random_boxes(10_000);
+        let mut reference = random_boxes(10_000);
+        let bvh = BVH::build_binned(&mut shapes, 4);
+        let reference_bvh = BVH::build(&mut reference);
+        let flat = bvh.flatten();
+        let enter = |ray: &Ray, shape: &UnitBox, t_max: f32| {
+            Some((ray.aabb_entry_distance(&shape.aabb(), t_max)?, shape.id))
+        };
+
+        let mut seed = 1;
+        let bounds = AABB::with_bounds(
+            Point3::new(-120.0, -120.0, -120.0),
+            Point3::new(120.0, 120.0, 120.0),
+        );
+        for _ in 0..100 {
+            let origin = next_point3(&mut seed, &bounds);
+            let direction = next_point3(&mut seed, &bounds);
+            let ray = Ray::new(origin, direction);
+
+            let mut found: Vec<_> = bvh
+                .traverse(&ray, &shapes)
+                .iter()
+                .map(|shape| shape.id)
+                .collect();
+            let mut expected: Vec<_> = shapes
+                .iter()
+                .filter(|shape| ray.intersects_aabb(&shape.aabb()))
+                .map(|shape| shape.id)
+                .collect();
+            found.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(found, expected);
+
+            // The iterator and the flat `BVH` find the same ones.
+            let mut iterated: Vec<_> = bvh
+                .traverse_iterator(&ray, &shapes)
+                .map(|shape| shape.id)
+                .collect();
+            let mut flat_found: Vec<_> = flat
+                .traverse(&ray, &shapes)
+                .iter()
+                .map(|shape| shape.id)
+                .collect();
+            iterated.sort_unstable();
+            flat_found.sort_unstable();
+            assert_eq!(iterated, expected);
+            assert_eq!(flat_found, expected);
+
+            let closest = bvh.closest_hit(&ray, &shapes, f32::INFINITY, |shape, t_max| {
+                enter(&ray, shape, t_max)
+            });
+            let reference_closest =
+                reference_bvh.closest_hit(&ray, &reference, f32::INFINITY, |shape, t_max| {
+                    enter(&ray, shape, t_max)
+                });
+            assert_eq!(closest, reference_closest);
+        }
+    }
+}
+
+#[cfg(all(feature = "bench", test))]
+mod bench {
+    use crate::bvh::BVH;
+    use crate::testbase::{create_n_cubes, default_bounds, intersect_bh, load_sponza_scene};
+
+    /// The leaf size the benchmarks build with, besides one shape per leaf.
+    const MAX_LEAF_SIZE: usize = 4;
+
+    #[bench]
+    /// Benchmark the binned construction of a `BVH` with 120,000 triangles.
+    fn bench_build_120k_triangles_binned_bvh(b: &mut ::test::Bencher) {
+        let mut triangles = create_n_cubes(10_000, &default_bounds());
+        b.iter(|| {
+            BVH::build_binned(&mut triangles, 1);
+        });
+    }
+
+    #[bench]
+    /// Benchmark the binned construction of a `BVH` with 120,000 triangles and larger leaves.
+    fn bench_build_120k_triangles_binned_leaves_bvh(b: &mut ::test::Bencher) {
+        let mut triangles = create_n_cubes(10_000, &default_bounds());
+        b.iter(|| {
+            BVH::build_binned(&mut triangles, MAX_LEAF_SIZE);
+        });
+    }
+
+    #[bench]
+    /// Benchmark the binned construction of a `BVH` for the Sponza scene.
+    fn bench_build_sponza_binned_bvh(b: &mut ::test::Bencher) {
+        let (mut triangles, _) = load_sponza_scene();
+        b.iter(|| {
+            BVH::build_binned(&mut triangles, 1);
+        });
+    }
+
+    #[bench]
+    /// Benchmark the binned construction of a `BVH` for the Sponza scene with larger leaves.
+    fn bench_build_sponza_binned_leaves_bvh(b: &mut ::test::Bencher) {
+        let (mut triangles, _) = load_sponza_scene();
+        b.iter(|| {
+            BVH::build_binned(&mut triangles, MAX_LEAF_SIZE);
+        });
+    }
+
+    #[bench]
+    /// Benchmark intersecting 120,000 triangles using a binned `BVH` with larger leaves.
+    fn bench_intersect_120k_triangles_binned_leaves_bvh(b: &mut ::test::Bencher) {
+        let bounds = default_bounds();
+        let mut triangles = create_n_cubes(10_000, &bounds);
+        let bvh = BVH::build_binned(&mut triangles, MAX_LEAF_SIZE);
+        intersect_bh(&bvh, &triangles, &bounds, b)
+    }
+
+    #[bench]
+    /// Benchmark the traversal of a binned `BVH` with larger leaves for the Sponza scene.
+    fn bench_intersect_sponza_binned_leaves_bvh(b: &mut ::test::Bencher) {
+        let (mut triangles, bounds) = load_sponza_scene();
+        let bvh = BVH::build_binned(&mut triangles, MAX_LEAF_SIZE);
+        intersect_bh(&bvh, &triangles, &bounds, b)
+    }
+}
@@ -12,10 +12,11 @@ use crate::utils::{concatenate_vectors, joint_aabb_of_shapes, Bucket};
 use crate::Point3;
 use crate::EPSILON;
 use std::f32;
+use std::ops::Range;
 
 /// The [`BVHNode`] enum that describes a node in a [`BVH`].
-/// It's either a leaf node and references a shape (by holding its index)
-/// or a regular node that has two child nodes.
+/// It's either a leaf node and references one or more shapes (by holding the index of the
+/// first one) or a regular node that has two child nodes.
 /// The non-leaf node stores the [`AABB`]s of its children.
 ///
 /// [`AABB`]: ../aabb/struct.AABB.html
@@ -34,8 +35,12 @@ pub enum BVHNode {
         /// The node's depth.
         depth: u32,
 
-        /// The shape contained in this leaf.
+        /// The first shape contained in this leaf.
         shape_index: usize,
+
+        /// The number of shapes in this leaf. They lie next to each other in the shapes
+        /// slice, starting at `shape_index`.
+        shape_count: usize,
     },
     /// Inner node.
     Node {
@@ -89,16 +94,19 @@ impl PartialEq for BVHNode {
                     parent_index: self_parent_index,
                     depth: self_depth,
                     shape_index: self_shape_index,
+                    shape_count: self_shape_count,
                 },
                 &BVHNode::Leaf {
                     parent_index: other_parent_index,
                     depth: other_depth,
                     shape_index: other_shape_index,
+                    shape_count: other_shape_count,
                 },
             ) => {
                 self_parent_index == other_parent_index
                     && self_depth == other_depth
                     && self_shape_index == other_shape_index
+                    && self_shape_count == other_shape_count
             }
             _ => false,
         }
@@ -189,7 +197,7 @@ impl BVHNode {
     }
 
     /// Gets the `AABB` for a `BVHNode`.
-    /// Returns the shape's `AABB` for leaves, and the joined `AABB` of
+    /// Returns the joined `AABB` of the shapes for leaves, and the joined `AABB` of
     /// the two children's `AABB`s for non-leaves.
     pub fn get_node_aabb<Shape: BHShape>(&self, shapes: &[Shape]) -> AABB {
         match *self {
@@ -198,11 +206,13 @@ impl BVHNode {
                 child_r_aabb,
                 ..
             } => child_l_aabb.join(&child_r_aabb),
-            BVHNode::Leaf { shape_index, .. } => shapes[shape_index].aabb(),
+            BVHNode::Leaf { .. } => self.shape_indices().fold(AABB::empty(), |aabb, index| {
+                aabb.join(&shapes[index].aabb())
+            }),
         }
     }
 
-    /// Returns the index of the shape contained within the node if is a leaf,
+    /// Returns the index of the first shape contained within the node if is a leaf,
     /// or `None` if it is an interior node.
     pub fn shape_index(&self) -> Option<usize> {
         match *self {
@@ -211,13 +221,27 @@ impl BVHNode {
         }
     }
 
+    /// Returns the indices of all shapes contained within the node if it is a leaf,
+    /// or an empty range if it is an interior node.
+    pub fn shape_indices(&self) -> Range<usize> {
+        match *self {
+            BVHNode::Leaf {
+                shape_index,
+                shape_count,
+                ..
+            } => shape_index..shape_index + shape_count,
+            _ => 0..0,
+        }
+    }
+
     /// The build function sometimes needs to add nodes while their data is not available yet.
     /// A dummy cerated by this function serves the purpose of being changed later on.
-    fn create_dummy() -> BVHNode {
+    pub(crate) fn create_dummy() -> BVHNode {
         BVHNode::Leaf {
             parent_index: 0,
             depth: 0,
             shape_index: 0,
+            shape_count: 0,
         }
     }
 
@@ -258,6 +282,7 @@ impl BVHNode {
                 parent_index,
                 depth,
                 shape_index,
+                shape_count: 1,
             });
             // Let the shape know the index of the node that represents it.
             shapes[shape_index].set_bh_node_index(node_index);
@@ -391,9 +416,122 @@ impl BVHNode {
                     BVHNode::traverse_recursive(nodes, child_r_index, ray, indices);
                 }
             }
-            BVHNode::Leaf { shape_index, .. } => {
-                indices.push(shape_index);
+            BVHNode::Leaf { .. } => {
+                indices.extend(nodes[node_index].shape_indices());
+            }
+        }
+    }
+
+    /// Like [`traverse_recursive`], but hands each shape index to `visit`
+    /// instead of collecting them.
+    ///
+    /// [`traverse_recursive`]: enum.BVHNode.html#method.traverse_recursive
+    ///
+    pub fn traverse_recursive_with<F: FnMut(usize)>(
+        nodes: &[BVHNode],
+        node_index: usize,
+        ray: &Ray,
+        visit: &mut F,
+    ) {
+        match nodes[node_index] {
+            BVHNode::Node {
+                ref child_l_aabb,
+                child_l_index,
+                ref child_r_aabb,
+                child_r_index,
+                ..
+            } => {
+                if ray.intersects_aabb(child_l_aabb) {
+                    BVHNode::traverse_recursive_with(nodes, child_l_index, ray, visit);
+                }
+                if ray.intersects_aabb(child_r_aabb) {
+                    BVHNode::traverse_recursive_with(nodes, child_r_index, ray, visit);
+                }
+            }
+            BVHNode::Leaf { .. } => nodes[node_index].shape_indices().for_each(visit),
+        }
+    }
+
+    /// Visits the children of a node nearest first, skipping those the [`Ray`] enters
+    /// beyond `t_max`, and passes shape indices to `intersect`. Every hit it reports
+    /// becomes the new `t_max`, so the rest of the traversal only looks for closer ones.
+    ///
+    /// [`Ray`]: ../ray/struct.Ray.html
+    ///
+    fn closest_hit_recursive<Hit, F: FnMut(usize, f32) -> Option<(f32, Hit)>>(
+        nodes: &[BVHNode],
+        node_index: usize,
+        ray: &Ray,
+        t_max: &mut f32,
+        closest: &mut Option<Hit>,
+        intersect: &mut F,
+    ) {
+        match nodes[node_index] {
+            BVHNode::Node {
+                ref child_l_aabb,
+                child_l_index,
+                ref child_r_aabb,
+                child_r_index,
+                ..
+            } => {
+                let l = ray.aabb_entry_distance(child_l_aabb, *t_max);
+                let r = ray.aabb_entry_distance(child_r_aabb, *t_max);
+                let (near, far) = match (l, r) {
+                    (Some(l), Some(r)) if r < l => ((child_r_index, r), Some((child_l_index, l))),
+                    (Some(l), r) => ((child_l_index, l), r.map(|r| (child_r_index, r))),
+                    (None, Some(r)) => ((child_r_index, r), None),
+                    (None, None) => return,
+                };
+                BVHNode::closest_hit_recursive(nodes, near.0, ray, t_max, closest, intersect);
+                if let Some((far_index, far_entry)) = far {
+                    // A hit in the near child may already be in front of the far one.
+                    if far_entry <= *t_max {
+                        BVHNode::closest_hit_recursive(
+                            nodes, far_index, ray, t_max, closest, intersect,
+                        );
+                    }
+                }
+            }
+            BVHNode::Leaf { .. } => {
+                for shape_index in nodes[node_index].shape_indices() {
+                    if let Some((t, hit)) = intersect(shape_index, *t_max) {
+                        if t < *t_max {
+                            *t_max = t;
+                            *closest = Some(hit);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns whether `blocks` is true for any shape index whose [`AABB`] the [`Ray`]
+    /// enters within `t_max`, stopping at the first one.
+    ///
+    /// [`AABB`]: ../aabb/struct.AABB.html
+    /// [`Ray`]: ../ray/struct.Ray.html
+    ///
+    fn any_hit_recursive<F: FnMut(usize) -> bool>(
+        nodes: &[BVHNode],
+        node_index: usize,
+        ray: &Ray,
+        t_max: f32,
+        blocks: &mut F,
+    ) -> bool {
+        match nodes[node_index] {
+            BVHNode::Node {
+                ref child_l_aabb,
+                child_l_index,
+                ref child_r_aabb,
+                child_r_index,
+                ..
+            } => {
+                (ray.aabb_entry_distance(child_l_aabb, t_max).is_some()
+                    && BVHNode::any_hit_recursive(nodes, child_l_index, ray, t_max, blocks))
+                    || (ray.aabb_entry_distance(child_r_aabb, t_max).is_some()
+                        && BVHNode::any_hit_recursive(nodes, child_r_index, ray, t_max, blocks))
             }
+            BVHNode::Leaf { .. } => nodes[node_index].shape_indices().any(blocks),
         }
     }
 }
@@ -432,7 +570,11 @@ impl BVH {
     /// [`BVH`]: struct.BVH.html
     /// [`AABB`]: ../aabb/struct.AABB.html
     ///
-    pub fn traverse<'a, Shape: Bounded>(&'a self, ray: &Ray, shapes: &'a [Shape]) -> Vec<&Shape> {
+    pub fn traverse<'a, Shape: Bounded>(
+        &'a self,
+        ray: &Ray,
+        shapes: &'a [Shape],
+    ) -> Vec<&'a Shape> {
         let mut indices = Vec::new();
         BVHNode::traverse_recursive(&self.nodes, 0, ray, &mut indices);
         indices
@@ -441,6 +583,108 @@ impl BVH {
             .collect::<Vec<_>>()
     }
 
+    /// Traverses the [`BVH`] without allocating, calling `visit` for each of
+    /// the `shapes` whose [`AABB`] is hit by `ray`.
+    ///
+    /// [`BVH`]: struct.BVH.html
+    /// [`AABB`]: ../aabb/struct.AABB.html
+    ///
+    pub fn traverse_with<'a, Shape: Bounded, F: FnMut(&'a Shape)>(
+        &self,
+        ray: &Ray,
+        shapes: &'a [Shape],
+        mut visit: F,
+    ) {
+        BVHNode::traverse_recursive_with(&self.nodes, 0, ray, &mut |index| visit(&shapes[index]));
+    }
+
+    /// Finds the nearest of the `shapes` hit by `ray` closer than `t_max`.
+    ///
+    /// `intersect` is called with each shape whose [`AABB`] the ray enters before the
+    /// nearest hit found so far, along with that hit's distance, and returns the distance
+    /// to the shape along with whatever the caller wants back about the hit, if the ray
+    /// hits the shape closer than that. Nodes are visited front to back, so far away
+    /// shapes are mostly never tested. Returns the nearest hit and its distance.
+    ///
+    /// [`AABB`]: ../aabb/struct.AABB.html
+    ///
+    pub fn closest_hit<'a, Shape: Bounded, Hit, F>(
+        &self,
+        ray: &Ray,
+        shapes: &'a [Shape],
+        t_max: f32,
+        mut intersect: F,
+    ) -> Option<(f32, Hit)>
+    where
+        F: FnMut(&'a Shape, f32) -> Option<(f32, Hit)>,
+    {
+        self.closest_hit_by_index(ray, t_max, |index, t_max| intersect(&shapes[index], t_max))
+    }
+
+    /// Like [`closest_hit`], but calls `intersect` with the index of each shape instead
+    /// of the shape itself, for shapes that can't be bounded on their own, such as
+    /// triangles that only hold indices into a vertex buffer.
+    ///
+    /// [`closest_hit`]: struct.BVH.html#method.closest_hit
+    ///
+    pub fn closest_hit_by_index<Hit, F>(
+        &self,
+        ray: &Ray,
+        t_max: f32,
+        mut intersect: F,
+    ) -> Option<(f32, Hit)>
+    where
+        F: FnMut(usize, f32) -> Option<(f32, Hit)>,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut t_max = t_max;
+        let mut closest = None;
+        BVHNode::closest_hit_recursive(
+            &self.nodes,
+            0,
+            ray,
+            &mut t_max,
+            &mut closest,
+            &mut intersect,
+        );
+        closest.map(|hit| (t_max, hit))
+    }
+
+    /// Returns whether `ray` hits any of the `shapes` closer than `t_max`, as decided by
+    /// `blocks` for each shape whose [`AABB`] the ray enters that close. Stops at the first
+    /// shape that blocks the ray, which makes it cheaper than [`closest_hit`] for shadow
+    /// rays.
+    ///
+    /// [`AABB`]: ../aabb/struct.AABB.html
+    /// [`closest_hit`]: struct.BVH.html#method.closest_hit
+    ///
+    pub fn any_hit<'a, Shape: Bounded, F: FnMut(&'a Shape) -> bool>(
+        &self,
+        ray: &Ray,
+        shapes: &'a [Shape],
+        t_max: f32,
+        mut blocks: F,
+    ) -> bool {
+        self.any_hit_by_index(ray, t_max, |index| blocks(&shapes[index]))
+    }
+
+    /// Like [`any_hit`], but calls `blocks` with the index of each shape instead of the
+    /// shape itself.
+    ///
+    /// [`any_hit`]: struct.BVH.html#method.any_hit
+    ///
+    pub fn any_hit_by_index<F: FnMut(usize) -> bool>(
+        &self,
+        ray: &Ray,
+        t_max: f32,
+        mut blocks: F,
+    ) -> bool {
+        !self.nodes.is_empty()
+            && BVHNode::any_hit_recursive(&self.nodes, 0, ray, t_max, &mut blocks)
+    }
+
     /// Creates a [`BVHTraverseIterator`] to traverse the [`BVH`].
     /// Returns a subset of `shapes`, in which the [`AABB`]s of the elements were hit by `ray`.
     ///
@@ -451,7 +695,7 @@ impl BVH {
         &'a self,
         ray: &'a Ray,
         shapes: &'a [Shape],
-    ) -> BVHTraverseIterator<Shape> {
+    ) -> BVHTraverseIterator<'a, Shape> {
         BVHTraverseIterator::new(self, ray, shapes)
     }
 
@@ -477,11 +721,11 @@ impl BVH {
                     println!("{}child_r {}", padding, child_r_aabb);
                     print_node(nodes, child_r_index);
                 }
-                BVHNode::Leaf {
-                    shape_index, depth, ..
-                } => {
+                BVHNode::Leaf { depth, .. } => {
                     let padding: String = " ".repeat(depth as usize);
-                    println!("{}shape\t{:?}", padding, shape_index);
+                    for shape_index in nodes[node_index].shape_indices() {
+                        println!("{}shape\t{:?}", padding, shape_index);
+                    }
                 }
             }
         }
@@ -490,7 +734,13 @@ impl BVH {
 
     /// Verifies that the node at index `node_index` lies inside `expected_outer_aabb`,
     /// its parent index is equal to `expected_parent_index`, its depth is equal to
-    /// `expected_depth`. Increares `node_count` by the number of visited nodes.
+    /// `expected_depth`. Increares `node_count` by the number of visited nodes and
+    /// `shape_counts` for each shape a visited leaf holds.
+    ///
+    /// Indices out of bounds make the subtree inconsistent rather than panicking, and
+    /// children are only visited once their parent checks out, so that a tree received
+    /// from elsewhere can be verified before it is used.
+    #[allow(clippy::too_many_arguments)]
     fn is_consistent_subtree<Shape: BHShape>(
         &self,
         node_index: usize,
@@ -498,82 +748,92 @@ impl BVH {
         expected_outer_aabb: &AABB,
         expected_depth: u32,
         node_count: &mut usize,
+        shape_counts: &mut [u32],
         shapes: &[Shape],
     ) -> bool {
         *node_count += 1;
-        match self.nodes[node_index] {
+        if *node_count > self.nodes.len() {
+            // Some node is reachable in more than one way.
+            return false;
+        }
+        let node = match self.nodes.get(node_index) {
+            Some(node) => node,
+            None => return false,
+        };
+        if node.parent() != expected_parent_index || node.depth() != expected_depth {
+            return false;
+        }
+        match *node {
             BVHNode::Node {
-                parent_index,
-                depth,
                 child_l_index,
                 child_l_aabb,
                 child_r_index,
                 child_r_aabb,
+                ..
             } => {
-                let correct_parent_index = expected_parent_index == parent_index;
-                let correct_depth = expected_depth == depth;
-                let left_aabb_in_parent =
-                    expected_outer_aabb.approx_contains_aabb_eps(&child_l_aabb, EPSILON);
-                let right_aabb_in_parent =
-                    expected_outer_aabb.approx_contains_aabb_eps(&child_r_aabb, EPSILON);
-                let left_subtree_consistent = self.is_consistent_subtree(
-                    child_l_index,
-                    node_index,
-                    &child_l_aabb,
-                    expected_depth + 1,
-                    node_count,
-                    shapes,
-                );
-                let right_subtree_consistent = self.is_consistent_subtree(
-                    child_r_index,
-                    node_index,
-                    &child_r_aabb,
-                    expected_depth + 1,
-                    node_count,
-                    shapes,
-                );
-
-                correct_parent_index
-                    && correct_depth
-                    && left_aabb_in_parent
-                    && right_aabb_in_parent
-                    && left_subtree_consistent
-                    && right_subtree_consistent
+                expected_outer_aabb.approx_contains_aabb_eps(&child_l_aabb, EPSILON)
+                    && expected_outer_aabb.approx_contains_aabb_eps(&child_r_aabb, EPSILON)
+                    && self.is_consistent_subtree(
+                        child_l_index,
+                        node_index,
+                        &child_l_aabb,
+                        expected_depth + 1,
+                        node_count,
+                        shape_counts,
+                        shapes,
+                    )
+                    && self.is_consistent_subtree(
+                        child_r_index,
+                        node_index,
+                        &child_r_aabb,
+                        expected_depth + 1,
+                        node_count,
+                        shape_counts,
+                        shapes,
+                    )
             }
             BVHNode::Leaf {
-                parent_index,
-                depth,
                 shape_index,
-            } => {
-                let correct_parent_index = expected_parent_index == parent_index;
-                let correct_depth = expected_depth == depth;
-                let shape_aabb = shapes[shape_index].aabb();
-                let shape_aabb_in_parent =
-                    expected_outer_aabb.approx_contains_aabb_eps(&shape_aabb, EPSILON);
-
-                correct_parent_index && correct_depth && shape_aabb_in_parent
-            }
+                shape_count,
+                ..
+            } => match shape_index.checked_add(shape_count) {
+                Some(end) if shape_count > 0 && end <= shapes.len() => {
+                    (shape_index..end).all(|index| {
+                        shape_counts[index] += 1;
+                        expected_outer_aabb.approx_contains_aabb_eps(&shapes[index].aabb(), EPSILON)
+                    })
+                }
+                _ => false,
+            },
         }
     }
 
     /// Checks if all children of a node have the correct parent index, and that there is no
-    /// detached subtree. Also checks if the `AABB` hierarchy is consistent.
+    /// detached subtree. Also checks if the `AABB` hierarchy is consistent, and that every
+    /// shape is held by exactly one leaf.
     pub fn is_consistent<Shape: BHShape>(&self, shapes: &[Shape]) -> bool {
+        // An empty hierarchy only fits an empty list of shapes.
+        if self.nodes.is_empty() {
+            return shapes.is_empty();
+        }
+
         // The root node of the bvh is not bounded by anything.
         let space = AABB {
             min: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
             max: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
         };
 
-        // The counter for all nodes.
+        // The counters for all nodes and for the leaves holding each shape.
         let mut node_count = 0;
+        let mut shape_counts = vec![0; shapes.len()];
         let subtree_consistent =
-            self.is_consistent_subtree(0, 0, &space, 0, &mut node_count, shapes);
+            self.is_consistent_subtree(0, 0, &space, 0, &mut node_count, &mut shape_counts, shapes);
 
         // Check if all nodes have been counted from the root node.
         // If this is false, it means we have a detached subtree.
         let is_connected = node_count == self.nodes.len();
-        subtree_consistent && is_connected
+        let shapes_held_once = shape_counts.iter().all(|&count| count == 1);
+        subtree_consistent && is_connected && shapes_held_once
     }
 
     /// Assert version of `is_consistent_subtree`.
@@ -643,14 +903,17 @@ impl BVH {
                     shapes,
                 );
             }
-            BVHNode::Leaf { shape_index, .. } => {
-                let shape_aabb = shapes[shape_index].aabb();
-                assert!(
-                    expected_outer_aabb.approx_contains_aabb_eps(&shape_aabb, EPSILON),
-                    "Shape's AABB lies outside the expected bounds.\n\tBounds: {}\n\tShape: {}",
-                    expected_outer_aabb,
-                    shape_aabb
-                );
+            BVHNode::Leaf { .. } => {
+                assert!(!node.shape_indices().is_empty(), "Empty leaf");
+                for shape_index in node.shape_indices() {
+                    let shape_aabb = shapes[shape_index].aabb();
+                    assert!(
+                        expected_outer_aabb.approx_contains_aabb_eps(&shape_aabb, EPSILON),
+                        "Shape's AABB lies outside the expected bounds.\n\tBounds: {}\n\tShape: {}",
+                        expected_outer_aabb,
+                        shape_aabb
+                    );
+                }
             }
         }
     }
@@ -713,7 +976,7 @@ impl BoundingHierarchy for BVH {
         BVH::build(shapes)
     }
 
-    fn traverse<'a, Shape: Bounded>(&'a self, ray: &Ray, shapes: &'a [Shape]) -> Vec<&Shape> {
+    fn traverse<'a, Shape: Bounded>(&'a self, ray: &Ray, shapes: &'a [Shape]) -> Vec<&'a Shape> {
         self.traverse(ray, shapes)
     }
 
@@ -724,8 +987,11 @@ impl BoundingHierarchy for BVH {
 
 #[cfg(test)]
 mod tests {
+    use crate::aabb::Bounded;
     use crate::bvh::{BVHNode, BVH};
-    use crate::testbase::{build_some_bh, traverse_some_bh};
+    use crate::ray::Ray;
+    use crate::testbase::{build_some_bh, traverse_some_bh, UnitBox};
+    use crate::{Point3, Vector3};
 
     #[test]
     /// Tests whether the building procedure succeeds in not failing.
@@ -766,6 +1032,132 @@ mod tests {
 
         assert_eq!(expected_shapes, found_shapes);
     }
+
+    #[test]
+    /// `traverse_with` visits the same shapes as `traverse`, in the same order.
+    fn test_traverse_with() {
+        let (all_shapes, bh) = build_some_bh::<BVH>();
+        let rays = [
+            (Point3::new(-1000.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0)),
+            (Point3::new(0.0, -1000.0, 0.0), Vector3::new(0.0, 1.0, 0.0)),
+            (Point3::new(6.0, 0.5, 0.0), Vector3::new(-1.0, 1.0, 0.0)),
+        ];
+        for (origin, direction) in rays {
+            let ray = Ray::new(origin, direction);
+            let mut visited = Vec::new();
+            bh.traverse_with(&ray, &all_shapes, |shape| visited.push(shape.id));
+            let expected: Vec<_> = bh
+                .traverse(&ray, &all_shapes)
+                .iter()
+                .map(|shape| shape.id)
+                .collect();
+            assert!(!expected.is_empty());
+            assert_eq!(visited, expected);
+        }
+    }
+
+    #[test]
+    /// `closest_hit` finds the box a ray enters first, testing few others on the way,
+    /// and `any_hit` only sees boxes within `t_max`.
+    fn test_closest_and_any_hit() {
+        let (all_shapes, bh) = build_some_bh::<BVH>();
+        let enter =
+            |ray: &Ray, shape: &UnitBox, t_max: f32| ray.aabb_entry_distance(&shape.aabb(), t_max);
+        for (x, direction, first) in [(-1000.0, 1.0, -10), (1000.0, -1.0, 10), (0.2, 1.0, 0)] {
+            let ray = Ray::new(Point3::new(x, 0.0, 0.0), Vector3::new(direction, 0.0, 0.0));
+            let mut tested = 0;
+            let (t, id) = bh
+                .closest_hit(&ray, &all_shapes, f32::INFINITY, |shape, t_max| {
+                    tested += 1;
+                    Some((enter(&ray, shape, t_max)?, shape.id))
+                })
+                .unwrap();
+            assert_eq!(id, first);
+            let nearest = all_shapes
+                .iter()
+                .filter_map(|shape| enter(&ray, shape, f32::INFINITY))
+                .fold(f32::INFINITY, f32::min);
+            assert_eq!(t, nearest);
+            assert!(tested < all_shapes.len() / 2, "tested {} boxes", tested);
+        }
+
+        let ray = Ray::new(Point3::new(-20.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        let blocks = |shape: &UnitBox| enter(&ray, shape, 9.0).is_some();
+        assert!(!bh.any_hit(&ray, &all_shapes, 9.0, blocks));
+        assert!(
+            bh.any_hit(&ray, &all_shapes, 9.6, |shape| enter(&ray, shape, 9.6)
+                .is_some())
+        );
+        let ray = Ray::new(Point3::new(0.0, 5.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        assert!(bh
+            .closest_hit(&ray, &all_shapes, f32::INFINITY, |shape, t_max| {
+                Some((enter(&ray, shape, t_max)?, ()))
+            })
+            .is_none());
+    }
+
+    #[test]
+    /// `is_consistent` rejects trees whose indices point at the wrong nodes or shapes,
+    /// without panicking.
+    fn test_is_consistent_rejects_damaged_trees() {
+        let (all_shapes, bh) = build_some_bh::<BVH>();
+        assert!(bh.is_consistent(&all_shapes));
+        assert!(!bh.is_consistent(&all_shapes[1..]));
+        assert!(!BVH { nodes: Vec::new() }.is_consistent(&all_shapes));
+        assert!(BVH { nodes: Vec::new() }.is_consistent::<UnitBox>(&[]));
+
+        let damaged = |damage: &dyn Fn(&mut BVHNode)| {
+            (0..bh.nodes.len()).any(|node_index| {
+                let mut nodes = bh.nodes.clone();
+                damage(&mut nodes[node_index]);
+                nodes[node_index] != bh.nodes[node_index]
+                    && BVH { nodes }.is_consistent(&all_shapes)
+            })
+        };
+        // Children pointing back at the root, past the end or at the same node.
+        assert!(!damaged(&|node| {
+            if let BVHNode::Node { child_l_index, .. } = node {
+                *child_l_index = 0;
+            }
+        }));
+        assert!(!damaged(&|node| {
+            if let BVHNode::Node { child_r_index, .. } = node {
+                *child_r_index = usize::MAX;
+            }
+        }));
+        assert!(!damaged(&|node| {
+            if let BVHNode::Node {
+                child_l_index,
+                child_r_index,
+                ..
+            } = node
+            {
+                *child_r_index = *child_l_index;
+            }
+        }));
+        // Leaves holding shapes that don't exist or belong to another leaf.
+        assert!(!damaged(&|node| {
+            if let BVHNode::Leaf { shape_index, .. } = node {
+                *shape_index = all_shapes.len();
+            }
+        }));
+        assert!(!damaged(&|node| {
+            if let BVHNode::Leaf { shape_count, .. } = node {
+                *shape_count = 2;
+            }
+        }));
+        // Ranges that don't fit in a `usize`.
+        assert!(!damaged(&|node| {
+            if let BVHNode::Leaf { shape_index, .. } = node {
+                *shape_index = usize::MAX;
+            }
+        }));
+        assert!(!damaged(&|node| {
+            if let BVHNode::Leaf { shape_count, .. } = node {
+                *shape_count = usize::MAX;
+            }
+        }));
+    }
 }
 
 #[cfg(all(feature = "bench", test))]
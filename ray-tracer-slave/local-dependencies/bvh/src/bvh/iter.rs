@@ -1,6 +1,7 @@
 use crate::aabb::Bounded;
 use crate::bvh::{BVHNode, BVH};
 use crate::ray::Ray;
+use std::ops::Range;
 
 /// Iterator to traverse a [`BVH`] without memory allocations
 #[allow(clippy::upper_case_acronyms)]
@@ -19,6 +20,8 @@ pub struct BVHTraverseIterator<'a, Shape: Bounded> {
     stack_size: usize,
     /// Whether or not we have a valid node (or leaf)
     has_node: bool,
+    /// Shapes of the last visited leaf that have not been returned yet
+    leaf_shapes: Range<usize>,
 }
 
 impl<'a, Shape: Bounded> BVHTraverseIterator<'a, Shape> {
@@ -32,6 +35,7 @@ impl<'a, Shape: Bounded> BVHTraverseIterator<'a, Shape> {
             node_index: 0,
             stack_size: 0,
             has_node: true,
+            leaf_shapes: 0..0,
         }
     }
 
@@ -110,6 +114,9 @@ impl<'a, Shape: Bounded> Iterator for BVHTraverseIterator<'a, Shape> {
 
     fn next(&mut self) -> Option<&'a Shape> {
         loop {
+            if let Some(shape_index) = self.leaf_shapes.next() {
+                return Some(&self.shapes[shape_index]);
+            }
             if self.is_stack_empty() && !self.has_node {
                 // Completed traversal.
                 break;
@@ -126,11 +133,11 @@ impl<'a, Shape: Bounded> Iterator for BVHTraverseIterator<'a, Shape> {
                         // If a node was pushed, now attempt to move to its right child.
                         self.move_right();
                     }
-                    BVHNode::Leaf { shape_index, .. } => {
+                    BVHNode::Leaf { .. } => {
                         // We previously pushed a leaf node. This is the "visit" of the in-order traverse.
-                        // Next time we call `next()` we try to pop the stack again.
+                        // Its shapes are returned first, then we try to pop the stack again.
                         self.has_node = false;
-                        return Some(&self.shapes[shape_index]);
+                        self.leaf_shapes = self.bvh.nodes[self.node_index].shape_indices();
                     }
                 }
             }
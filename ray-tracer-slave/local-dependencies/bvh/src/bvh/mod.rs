@@ -3,10 +3,10 @@
 //! [`BVH`]: struct.BVH.html
 //!
 
+mod binned;
 mod bvh_impl;
 mod iter;
 mod optimization;
 
 pub use self::bvh_impl::*;
 pub use self::iter::*;
-pub use self::optimization::*;
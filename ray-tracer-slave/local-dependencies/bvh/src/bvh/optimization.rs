@@ -162,16 +162,12 @@ impl BVH {
         info!("   [{}]\t", node_index);
 
         match self.nodes[node_index] {
-            BVHNode::Leaf {
-                parent_index,
-                shape_index,
-                ..
-            } => {
+            BVHNode::Leaf { parent_index, .. } => {
                 // The current node is a leaf.
                 info!(
                     "Leaf node. Queueing parent ({}). {}.",
                     parent_index,
-                    shapes[shape_index].aabb()
+                    self.nodes[node_index].get_node_aabb(shapes)
                 );
                 Some(OptimizationIndex::Refit(parent_index))
             }
@@ -182,17 +178,11 @@ impl BVH {
                 ..
             } => {
                 // The current node is a parent.
-                if let (
-                    &BVHNode::Leaf {
-                        shape_index: shape_l_index,
-                        ..
-                    },
-                    &BVHNode::Leaf {
-                        shape_index: shape_r_index,
-                        ..
-                    },
-                ) = (&self.nodes[child_l_index], &self.nodes[child_r_index])
+                if let (&BVHNode::Leaf { .. }, &BVHNode::Leaf { .. }) =
+                    (&self.nodes[child_l_index], &self.nodes[child_r_index])
                 {
+                    let new_child_l_aabb = self.nodes[child_l_index].get_node_aabb(shapes);
+                    let new_child_r_aabb = self.nodes[child_r_index].get_node_aabb(shapes);
                     // The current node is a final parent. Update its `AABB`s, because at least
                     // one of its children was updated and queue its parent for refitting.
                     if let BVHNode::Node {
@@ -201,8 +191,8 @@ impl BVH {
                         ..
                     } = self.nodes[node_index]
                     {
-                        *child_l_aabb = shapes[shape_l_index].aabb();
-                        *child_r_aabb = shapes[shape_r_index].aabb();
+                        *child_l_aabb = new_child_l_aabb;
+                        *child_r_aabb = new_child_r_aabb;
                         info!("Setting {} from {}", child_l_aabb, child_l_index);
                         info!("\tand {} from {}.", child_r_aabb, child_r_index);
                         return Some(OptimizationIndex::Refit(parent_index));
@@ -668,21 +658,25 @@ mod tests {
                 parent_index: 1,
                 depth: 2,
                 shape_index: 0,
+                shape_count: 1,
             },
             BVHNode::Leaf {
                 parent_index: 1,
                 depth: 2,
                 shape_index: 1,
+                shape_count: 1,
             },
             BVHNode::Leaf {
                 parent_index: 2,
                 depth: 2,
                 shape_index: 2,
+                shape_count: 1,
             },
             BVHNode::Leaf {
                 parent_index: 2,
                 depth: 2,
                 shape_index: 3,
+                shape_count: 1,
             },
         ];
 
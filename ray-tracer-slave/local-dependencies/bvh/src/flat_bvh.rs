@@ -10,6 +10,7 @@ use crate::ray::Ray;
 ///
 /// [`BVH`]: ../bvh/struct.BVH.html
 ///
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FlatNode {
     /// The [`AABB`] of the [`BVH`] node. Prior to testing the [`AABB`] bounds,
     /// the `entry_index` must be checked. In case the entry_index is [`u32::max_value()`],
@@ -114,16 +115,19 @@ impl BVHNode {
                     constructor,
                 )
             }
-            BVHNode::Leaf { shape_index, .. } => {
+            BVHNode::Leaf { .. } => {
+                // Every shape of the leaf gets a flat leaf node of its own.
                 let mut next_shape = next_free;
-                next_shape += 1;
-                let leaf_node = constructor(
-                    &AABB::empty(),
-                    u32::max_value(),
-                    next_shape as u32,
-                    shape_index as u32,
-                );
-                vec.push(leaf_node);
+                for shape_index in self.shape_indices() {
+                    next_shape += 1;
+                    let leaf_node = constructor(
+                        &AABB::empty(),
+                        u32::max_value(),
+                        next_shape as u32,
+                        shape_index as u32,
+                    );
+                    vec.push(leaf_node);
+                }
 
                 next_shape
             }
@@ -378,7 +382,7 @@ impl BoundingHierarchy for FlatBVH {
     /// let flat_bvh = FlatBVH::build(&mut shapes);
     /// let hit_shapes = flat_bvh.traverse(&ray, &shapes);
     /// ```
-    fn traverse<'a, T: Bounded>(&'a self, ray: &Ray, shapes: &'a [T]) -> Vec<&T> {
+    fn traverse<'a, T: Bounded>(&'a self, ray: &Ray, shapes: &'a [T]) -> Vec<&'a T> {
         let mut hit_shapes = Vec::new();
         let mut index = 0;
 
@@ -136,9 +136,10 @@ impl Ray {
             origin,
             direction,
             inv_direction: Vector3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z),
-            sign_x: (direction.x < 0.0) as usize,
-            sign_y: (direction.y < 0.0) as usize,
-            sign_z: (direction.z < 0.0) as usize,
+            // Negative zero must count as negative, as its inverse is negative infinity.
+            sign_x: direction.x.is_sign_negative() as usize,
+            sign_y: direction.y.is_sign_negative() as usize,
+            sign_z: direction.z.is_sign_negative() as usize,
         }
     }
 
@@ -193,6 +194,53 @@ impl Ray {
         max(ray_min, 0.0) <= ray_max
     }
 
+    /// Returns how far along the [`Ray`] it enters `aabb`, or `0.0` if it starts inside,
+    /// provided that is no further than `t_max`.
+    ///
+    /// # Examples
+    /// ```
+    /// use bvh::aabb::AABB;
+    /// use bvh::ray::Ray;
+    /// use bvh::{Point3,Vector3};
+    ///
+    /// let origin = Point3::new(0.0,0.0,0.0);
+    /// let direction = Vector3::new(1.0,0.0,0.0);
+    /// let ray = Ray::new(origin, direction);
+    ///
+    /// let point1 = Point3::new(99.9,-1.0,-1.0);
+    /// let point2 = Point3::new(100.1,1.0,1.0);
+    /// let aabb = AABB::with_bounds(point1, point2);
+    ///
+    /// assert_eq!(ray.aabb_entry_distance(&aabb, f32::INFINITY), Some(99.9));
+    /// assert_eq!(ray.aabb_entry_distance(&aabb, 50.0), None);
+    /// ```
+    ///
+    /// [`Ray`]: struct.Ray.html
+    /// [`AABB`]: struct.AABB.html
+    ///
+    pub fn aabb_entry_distance(&self, aabb: &AABB, t_max: f32) -> Option<f32> {
+        let mut ray_min = (aabb[self.sign_x].x - self.origin.x) * self.inv_direction.x;
+        let mut ray_max = (aabb[1 - self.sign_x].x - self.origin.x) * self.inv_direction.x;
+
+        let y_min = (aabb[self.sign_y].y - self.origin.y) * self.inv_direction.y;
+        let y_max = (aabb[1 - self.sign_y].y - self.origin.y) * self.inv_direction.y;
+
+        ray_min = max(ray_min, y_min);
+        ray_max = min(ray_max, y_max);
+
+        let z_min = (aabb[self.sign_z].z - self.origin.z) * self.inv_direction.z;
+        let z_max = (aabb[1 - self.sign_z].z - self.origin.z) * self.inv_direction.z;
+
+        ray_min = max(max(ray_min, z_min), 0.0);
+        ray_max = min(min(ray_max, z_max), t_max);
+
+        if ray_min <= ray_max {
+            Some(ray_min)
+        } else {
+            None
+        }
+    }
+
     /// Naive implementation of a [`Ray`]/[`AABB`] intersection algorithm.
     ///
     /// # Examples
@@ -510,6 +558,18 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    /// Axis-aligned rays whose other components are negative zero still hit flat boxes.
+    fn test_negative_zero_direction() {
+        let aabb = AABB::with_bounds(
+            crate::Point3::new(0.0, 0.0, 0.0),
+            crate::Point3::new(8.0, 4.0, 0.0),
+        );
+        let ray = Ray::new(crate::Point3::new(0.25, 0.5, 5.0), -crate::Vector3::Z);
+        assert_eq!(ray.aabb_entry_distance(&aabb, INFINITY), Some(5.0));
+        assert!(ray.intersects_aabb(&aabb));
+    }
 }
 
 #[cfg(all(feature = "bench", test))]
@@ -0,0 +1,202 @@
+//! Renders a scene file or an OBJ/MTL pair on this machine alone, with the
+//! same integrator the slaves use, and writes the image to a file.
+use clap::Parser;
+use log::info;
+use ray_tracer_interface::{
+    camera::CameraSettings,
+    environment::EnvironmentMap,
+    obj,
+    output::{self, LdrSettings, OutputFormat, Tonemap},
+    render::{render_pass, World},
+    scene::{Background, RenderSettings, Scene},
+    world_hash, RenderMeta, WorkItem, AABB,
+};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(about = "Render a scene locally on all cores")]
+struct Args {
+    /// Scene file (`.json`) or OBJ model.
+    input: PathBuf,
+    /// MTL file for an OBJ model; defaults to the model's path with `.mtl`.
+    mtl: Option<PathBuf>,
+    #[arg(short, long, default_value = "out.png")]
+    output: PathBuf,
+    /// `jpeg`, `png`, `png16`, `exr` or `hdr`; guessed from the output file
+    /// name by default.
+    #[arg(short, long)]
+    format: Option<OutputFormat>,
+    #[arg(long)]
+    width: Option<u32>,
+    #[arg(long)]
+    height: Option<u32>,
+    #[arg(short, long)]
+    samples_per_pixel: Option<u32>,
+    #[arg(long)]
+    max_depth: Option<u32>,
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long)]
+    tile_size: Option<u32>,
+    /// Defaults to the scene's gamma.
+    #[arg(long)]
+    gamma: Option<f32>,
+    /// `clamp`, `reinhard` or `aces`, for LDR formats.
+    #[arg(long, default_value = "clamp")]
+    tonemap: Tonemap,
+    /// In stops, for LDR formats.
+    #[arg(long, default_value_t = 0f32)]
+    exposure: f32,
+}
+
+impl Args {
+    fn apply(&self, render: &mut RenderSettings) {
+        if let Some(width) = self.width {
+            render.width = width;
+        }
+        if let Some(height) = self.height {
+            render.height = height;
+        }
+        if let Some(samples_per_pixel) = self.samples_per_pixel {
+            render.samples_per_pixel = samples_per_pixel;
+        }
+        if let Some(max_depth) = self.max_depth {
+            render.max_depth = max_depth;
+        }
+        if let Some(tile_size) = self.tile_size {
+            render.tile_size = tile_size;
+        }
+        if let Some(seed) = self.seed {
+            render.seed = Some(seed);
+        }
+        if let Some(gamma) = self.gamma {
+            render.gamma = gamma;
+        }
+    }
+}
+
+fn main() {
+    pretty_env_logger::init();
+    if let Err(e) = render(Args::parse()) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn render(args: Args) -> Result<(), Box<dyn Error>> {
+    let format = match args.format {
+        Some(format) => format,
+        None => OutputFormat::from_extension(&args.output)
+            .ok_or("can't tell the output format from the file name, pass --format")?,
+    };
+
+    let (mut render, camera, background, lights, world) = if args
+        .input
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        let scene = Scene::from_json(&std::fs::read(&args.input)?)?;
+        let world = scene.build_world()?;
+        (
+            scene.render,
+            scene.camera,
+            scene.background,
+            scene.lights,
+            world,
+        )
+    } else {
+        let mtl = args
+            .mtl
+            .clone()
+            .unwrap_or_else(|| args.input.with_extension("mtl"));
+        let world = obj::build_world(&std::fs::read(&args.input)?, &std::fs::read(mtl)?)?;
+        let bounds = world
+            .iter()
+            .fold(AABB::empty(), |bounds, object| bounds.join_bounded(object));
+        (
+            RenderSettings::default(),
+            CameraSettings::framing(&bounds),
+            Background::default(),
+            Vec::new(),
+            world,
+        )
+    };
+    args.apply(&mut render);
+    render.validate()?;
+    // Scene files name their environment map relative to themselves.
+    let environment = match background.environment_map() {
+        Some(path) => Some(EnvironmentMap::decode(&std::fs::read(
+            args.input.with_file_name(path),
+        )?)?),
+        None => None,
+    };
+
+    let seed = render.seed.unwrap_or_else(rand::random);
+    info!("rendering {} objects with seed {}", world.len(), seed);
+    // Laid out like a job without progressive passes, so a render with the
+    // same seed and tile size comes out the same as on the slaves.
+    let render_meta = RenderMeta {
+        height: render.height,
+        width: render.width,
+        tile_size: render.tile_size,
+        samples_per_pixel: render.samples_per_pixel,
+        max_depth: render.max_depth,
+        seed,
+        gamma: render.gamma,
+        progressive: false,
+        id: Uuid::nil(),
+    };
+    let work = WorkItem {
+        world: world_hash(&world),
+        camera: camera.build(render.width, render.height),
+        background,
+        lights,
+        tile_no: 0,
+        pass: 0,
+        samples: render.samples_per_pixel,
+        render_meta,
+    };
+    let world = World::new(world);
+
+    let tiles = work.render_meta.tile_count();
+    let done = AtomicU32::new(0);
+    let slices: Vec<Vec<f32>> = (0..tiles)
+        .into_par_iter()
+        .map(|tile_no| {
+            let work = WorkItem {
+                tile_no,
+                ..work.clone()
+            };
+            let slice = render_pass(&work, &world, environment.as_ref(), || false).unwrap();
+            info!(
+                "tile {}/{}",
+                done.fetch_add(1, Ordering::Relaxed) + 1,
+                tiles
+            );
+            slice
+        })
+        .collect();
+
+    let width = render.width as usize;
+    let mut radiance = vec![0f32; width * render.height as usize * 3];
+    for (tile_no, slice) in slices.iter().enumerate() {
+        let tile = work.render_meta.tile(tile_no as u32);
+        for (row, pixels) in slice.chunks_exact(tile.width as usize * 3).enumerate() {
+            let start = ((tile.y as usize + row) * width + tile.x as usize) * 3;
+            radiance[start..start + pixels.len()].copy_from_slice(pixels);
+        }
+    }
+    let ldr = LdrSettings {
+        tonemap: args.tonemap,
+        exposure: args.exposure,
+        gamma: render.gamma,
+    };
+    let image = output::encode(render.width, render.height, radiance, format, &ldr)?;
+    std::fs::write(&args.output, image)?;
+    info!("wrote {}", args.output.display());
+    Ok(())
+}
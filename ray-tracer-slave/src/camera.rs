@@ -1,127 +1,160 @@
-use bvh::{ray::Ray, Point3, Vector3};
+use bvh::{aabb::AABB, ray::Ray, Point3, Vector3};
 use rand::{rngs::SmallRng, Rng};
 use rand_distr::{Distribution, UnitDisc};
+use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CameraSettings {
+    #[serde(alias = "origin")]
+    pub look_from: Point3,
+    pub look_at: Point3,
+    pub up: Vector3,
+    /// Vertical field of view in degrees.
+    pub field_of_view: f32,
+    pub aperture: f32,
+    pub focus_distance: f32,
+    pub focal_length: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            look_from: Point3::ZERO,
+            look_at: Point3::new(0f32, 0f32, -1f32),
+            up: Vector3::Y,
+            field_of_view: 90f32,
+            aperture: 0.1f32,
+            focus_distance: 1f32,
+            focal_length: 1f32,
+        }
+    }
+}
+
+impl CameraSettings {
+    /// Places the camera on the +Z side of `bounds`, far enough back for the
+    /// whole box to fit in the default field of view, focused on its centre.
+    pub fn framing(bounds: &AABB) -> Self {
+        let defaults = Self::default();
+        if bounds.is_empty() {
+            return defaults;
+        }
+        let look_at = bounds.center();
+        let radius = bounds.size().length() / 2f32;
+        let distance = radius / (defaults.field_of_view.to_radians() / 2f32).sin();
+        Self {
+            look_from: look_at + Vector3::new(0f32, 0f32, distance),
+            look_at,
+            focus_distance: distance,
+            ..defaults
+        }
+    }
+
+    pub fn build(&self, image_width: u32, image_height: u32) -> Camera {
+        Camera::new(self.clone(), image_width, image_height)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Camera {
-    origin: Point3,
+    settings: CameraSettings,
+    image_width: u32,
+    image_height: u32,
+    u: Vector3,
+    v: Vector3,
     lower_left_corner: Point3,
     horizontal: Vector3,
-    aspect_ratio: f32,
-    image_height: f32,
     vertical: Vector3,
-    aperture: f32,
-    focal_length: f32,
-    field_of_view: f32,
-    focus_distance: f32,
 }
 
 impl Camera {
-    pub fn new(
-        origin: Point3,
-        aspect_ratio: f32,
-        aperture: f32,
-        focus_distance: f32,
-        field_of_view: f32,
-        focal_length: f32,
-        image_height: f32,
-    ) -> Self {
-        let vh = 2.0 * (field_of_view / 2f32).tan();
+    pub fn new(settings: CameraSettings, image_width: u32, image_height: u32) -> Self {
+        let aspect_ratio = image_width as f32 / image_height as f32;
+        let vh = 2.0 * (settings.field_of_view.to_radians() / 2f32).tan();
         let vw = aspect_ratio * vh;
-        let horizontal = Vector3::new(vw, 0f32, 0f32);
-        let vertical = Vector3::new(0f32, vh, 0f32);
+        let w = (settings.look_from - settings.look_at)
+            .try_normalize()
+            .unwrap_or(Vector3::Z);
+        let u = settings
+            .up
+            .cross(w)
+            .try_normalize()
+            .unwrap_or_else(|| w.any_orthonormal_vector());
+        let v = w.cross(u);
+        let horizontal = vw * u;
+        let vertical = vh * v;
         Self {
-            origin,
-            horizontal,
-            focus_distance,
-            image_height,
-            field_of_view,
-            focal_length,
-            aspect_ratio,
-            vertical,
-            aperture,
-            lower_left_corner: origin
+            lower_left_corner: settings.look_from
                 - horizontal / 2f32
                 - vertical / 2f32
-                - Vector3::new(0f32, 0f32, focal_length),
+                - settings.focal_length * w,
+            settings,
+            image_width,
+            image_height,
+            u,
+            v,
+            horizontal,
+            vertical,
         }
     }
 
-    pub fn set_field_of_view(&mut self, field_of_view: f32) {
-        *self = Self::new(
-            self.origin,
-            self.aspect_ratio,
-            self.aperture,
-            self.focus_distance,
-            field_of_view,
-            self.focal_length,
-            self.image_height,
-        )
+    fn rebuild(&mut self) {
+        *self = Self::new(self.settings.clone(), self.image_width, self.image_height)
     }
 
-    pub fn set_focal_length(&mut self, focal_length: f32) {
-        *self = Self::new(
-            self.origin,
-            self.aspect_ratio,
-            self.aperture,
-            self.focus_distance,
-            self.field_of_view,
-            focal_length,
-            self.image_height,
-        )
+    pub fn settings(&self) -> &CameraSettings {
+        &self.settings
     }
 
-    pub fn set_origin(&mut self, origin: Point3) {
-        *self = Self::new(
-            origin,
-            self.aspect_ratio,
-            self.aperture,
-            self.focus_distance,
-            self.field_of_view,
-            self.focal_length,
-            self.image_height,
-        )
+    pub fn set_look_from(&mut self, look_from: Point3) {
+        self.settings.look_from = look_from;
+        self.rebuild()
+    }
+
+    pub fn set_look_at(&mut self, look_at: Point3) {
+        self.settings.look_at = look_at;
+        self.rebuild()
+    }
+
+    pub fn set_up(&mut self, up: Vector3) {
+        self.settings.up = up;
+        self.rebuild()
+    }
+
+    pub fn set_field_of_view(&mut self, field_of_view: f32) {
+        self.settings.field_of_view = field_of_view;
+        self.rebuild()
+    }
+
+    pub fn set_focal_length(&mut self, focal_length: f32) {
+        self.settings.focal_length = focal_length;
+        self.rebuild()
     }
 
     pub fn set_focus_distance(&mut self, focus_distance: f32) {
-        *self = Self::new(
-            self.origin,
-            self.aspect_ratio,
-            self.aperture,
-            focus_distance,
-            self.field_of_view,
-            self.focal_length,
-            self.image_height,
-        )
+        self.settings.focus_distance = focus_distance;
+        self.rebuild()
     }
 
     pub fn set_aperture(&mut self, aperture: f32) {
-        *self = Self::new(
-            self.origin,
-            self.aspect_ratio,
-            aperture,
-            self.focus_distance,
-            self.field_of_view,
-            self.focal_length,
-            self.image_height,
-        )
+        self.settings.aperture = aperture;
+        self.rebuild()
     }
 
     pub fn get_ray(&self, x: u32, y: u32, rng: &mut SmallRng) -> Ray {
-        let lens_radius = self.aperture / 2f32;
-        let offset = Vector3::from_slice({
-            let [a, b]: [f32; 2] = UnitDisc.sample(rng);
-            &[a * lens_radius, b * lens_radius, 0f32]
-        });
-        let u =
-            (x as f32 + rng.gen_range(0f32..1f32)) / (self.aspect_ratio * self.image_height - 1f32);
-        let v = (y as f32 + rng.gen_range(0f32..1f32)) / (self.image_height - 1f32);
+        let lens_radius = self.settings.aperture / 2f32;
+        let [a, b]: [f32; 2] = UnitDisc.sample(rng);
+        let offset = a * lens_radius * self.u + b * lens_radius * self.v;
+        let u = (x as f32 + rng.gen_range(0f32..1f32)) / (self.image_width as f32 - 1f32);
+        let v = (y as f32 + rng.gen_range(0f32..1f32)) / (self.image_height as f32 - 1f32);
+        let origin = self.settings.look_from;
         let focal_point = Ray::new(
-            self.origin,
-            (self.lower_left_corner + u * self.horizontal + v * self.vertical - self.origin)
+            origin,
+            (self.lower_left_corner + u * self.horizontal + v * self.vertical - origin)
                 .normalize_or_zero(),
         )
-        .at(self.focus_distance);
-        let final_ray_origin = self.origin + offset;
+        .at(self.settings.focus_distance);
+        let final_ray_origin = origin + offset;
         Ray::new(
             final_ray_origin,
             (focal_point - final_ray_origin).normalize_or_zero(),
@@ -26,6 +26,15 @@ impl Color {
         }
     }
 
+    pub fn gamma_correct(&self, gamma: f32) -> Self {
+        let exponent = 1f32 / gamma;
+        Self {
+            r: self.r.powf(exponent),
+            g: self.g.powf(exponent),
+            b: self.b.powf(exponent),
+        }
+    }
+
     pub fn blend(&self, other: &Self) -> Self {
         Self {
             r: self.r * other.r,
@@ -34,6 +43,11 @@ impl Color {
         }
     }
 
+    /// Perceived brightness, as in Rec. 709.
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
     pub fn random() -> Self {
         let mut rng = thread_rng();
         Self {
@@ -58,7 +72,7 @@ impl ops::Add for Color {
 
 impl ops::AddAssign for Color {
     fn add_assign(&mut self, rhs: Self) {
-        *self = self.clone() + rhs;
+        *self = *self + rhs;
     }
 }
 
@@ -0,0 +1,234 @@
+//! Equirectangular environment maps, importance sampled by brightness so the
+//! few texels of a sun or a window that light most of a scene are found
+//! quickly.
+use crate::color::Color;
+use bvh::Vector3;
+use image::codecs::hdr::HdrDecoder;
+use image::{ImageFormat, ImageResult};
+use rand::rngs::SmallRng;
+use rand::Rng;
+use std::f32::consts::PI;
+
+/// Piecewise constant density over `[0, 1)`, one piece per entry of `func`.
+struct Distribution1D {
+    func: Vec<f32>,
+    /// Normalized running total, starting at 0 and ending at 1.
+    cdf: Vec<f32>,
+    /// Mean of `func`, its integral over `[0, 1)`.
+    integral: f32,
+}
+
+impl Distribution1D {
+    fn new(func: Vec<f32>) -> Self {
+        let n = func.len() as f32;
+        let mut cdf = Vec::with_capacity(func.len() + 1);
+        cdf.push(0f32);
+        for value in &func {
+            cdf.push(cdf.last().unwrap() + value / n);
+        }
+        let integral = *cdf.last().unwrap();
+        for (i, value) in cdf.iter_mut().enumerate() {
+            *value = if integral > 0f32 {
+                *value / integral
+            } else {
+                // Nothing to go by, so spread it out evenly.
+                i as f32 / n
+            };
+        }
+        Self {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    /// Picks a point in `[0, 1)`, returning it, its density and its piece.
+    fn sample(&self, u: f32) -> (f32, f32, usize) {
+        let piece = (self.cdf.partition_point(|c| *c <= u) - 1).min(self.func.len() - 1);
+        let start = self.cdf[piece];
+        let width = self.cdf[piece + 1] - start;
+        let offset = if width > 0f32 {
+            (u - start) / width
+        } else {
+            0f32
+        };
+        let x = (piece as f32 + offset) / self.func.len() as f32;
+        (x, self.pdf(piece), piece)
+    }
+
+    fn pdf(&self, piece: usize) -> f32 {
+        if self.integral > 0f32 {
+            self.func[piece] / self.integral
+        } else {
+            1f32
+        }
+    }
+}
+
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    /// Linear radiance, row-major from the top, which is straight up.
+    pixels: Vec<Color>,
+    /// Picks a row, then a column within it.
+    rows: Distribution1D,
+    columns: Vec<Distribution1D>,
+}
+
+impl EnvironmentMap {
+    /// Reads a Radiance HDR file, or any other format the image crate knows,
+    /// typically OpenEXR.
+    pub fn decode(data: &[u8]) -> ImageResult<Self> {
+        if image::guess_format(data)? == ImageFormat::Hdr {
+            // Going through `DynamicImage` would tonemap it to 8 bits.
+            let decoder = HdrDecoder::new(data)?;
+            let meta = decoder.metadata();
+            let pixels = decoder
+                .read_image_hdr()?
+                .into_iter()
+                .map(|pixel| Color::from_slice(pixel.0))
+                .collect();
+            return Ok(Self::new(meta.width as usize, meta.height as usize, pixels));
+        }
+        let image = image::load_from_memory(data)?.into_rgb32f();
+        let pixels = image
+            .pixels()
+            .map(|pixel| Color::from_slice(pixel.0))
+            .collect();
+        Ok(Self::new(
+            image.width() as usize,
+            image.height() as usize,
+            pixels,
+        ))
+    }
+
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>) -> Self {
+        // Rows near the poles are squeezed into less solid angle.
+        let columns: Vec<Distribution1D> = pixels
+            .chunks_exact(width)
+            .enumerate()
+            .map(|(y, row)| {
+                let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+                Distribution1D::new(
+                    row.iter()
+                        .map(|pixel| pixel.luminance().max(0f32) * sin_theta)
+                        .collect(),
+                )
+            })
+            .collect();
+        let rows = Distribution1D::new(columns.iter().map(|row| row.integral).collect());
+        Self {
+            width,
+            height,
+            pixels,
+            rows,
+            columns,
+        }
+    }
+
+    fn texel(&self, u: f32, v: f32) -> (usize, usize) {
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        (x, y)
+    }
+
+    /// Radiance arriving from `direction`, given in the map's own frame.
+    pub fn radiance(&self, direction: Vector3) -> Color {
+        let (u, v) = to_uv(direction);
+        let (x, y) = self.texel(u, v);
+        self.pixels[y * self.width + x]
+    }
+
+    /// Picks a direction, in the map's frame, in proportion to how bright
+    /// the map is there. Returns it with its radiance and density per solid
+    /// angle.
+    pub fn sample(&self, rng: &mut SmallRng) -> Option<(Vector3, Color, f32)> {
+        let (v, row_pdf, y) = self.rows.sample(rng.gen());
+        let (u, column_pdf, x) = self.columns[y].sample(rng.gen());
+        let sin_theta = (PI * v).sin();
+        if sin_theta <= 0f32 {
+            return None;
+        }
+        let pdf = row_pdf * column_pdf / (2f32 * PI * PI * sin_theta);
+        Some((from_uv(u, v), self.pixels[y * self.width + x], pdf))
+    }
+
+    /// Density `sample` picks `direction` with.
+    pub fn pdf(&self, direction: Vector3) -> f32 {
+        let (u, v) = to_uv(direction);
+        let sin_theta = (PI * v).sin();
+        if sin_theta <= 0f32 {
+            return 0f32;
+        }
+        let (x, y) = self.texel(u, v);
+        self.rows.pdf(y) * self.columns[y].pdf(x) / (2f32 * PI * PI * sin_theta)
+    }
+}
+
+/// Maps a direction to the map, with up at the top and -Z in the middle.
+fn to_uv(direction: Vector3) -> (f32, f32) {
+    let direction = direction.normalize_or_zero();
+    let phi = direction.x.atan2(-direction.z);
+    let u = (phi / (2f32 * PI) + 0.5).rem_euclid(1f32);
+    // Unlike acos, this stays precise near the poles.
+    let v = direction.x.hypot(direction.z).atan2(direction.y) / PI;
+    (u, v)
+}
+
+fn from_uv(u: f32, v: f32) -> Vector3 {
+    let phi = (u - 0.5) * 2f32 * PI;
+    let theta = v * PI;
+    Vector3::new(
+        theta.sin() * phi.sin(),
+        theta.cos(),
+        -theta.sin() * phi.cos(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_distr::{Distribution, UnitSphere};
+
+    #[test]
+    fn test_uv_round_trip() {
+        for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.7), (0.25, 0.99)] {
+            let (u2, v2) = to_uv(from_uv(u, v));
+            assert!((u - u2).abs() < 1e-4 && (v - v2).abs() < 1e-4);
+        }
+        assert!((to_uv(Vector3::Y).1).abs() < 1e-6);
+        assert_eq!(to_uv(-Vector3::Z), (0.5, 0.5));
+    }
+
+    /// A bright spot gets most samples, and the densities `sample` returns
+    /// agree with `pdf` and integrate to one over the sphere.
+    #[test]
+    fn test_sampling() {
+        let (width, height) = (16, 8);
+        let mut pixels = vec![Color::from_slice([0.1, 0.1, 0.1]); width * height];
+        pixels[3 * width + 5] = Color::from_slice([100.0, 100.0, 100.0]);
+        let map = EnvironmentMap::new(width, height, pixels);
+        let mut rng = SmallRng::seed_from_u64(9);
+        let n = 10000;
+        let mut bright = 0;
+        for _ in 0..n {
+            let (direction, radiance, pdf) = map.sample(&mut rng).unwrap();
+            assert!((map.pdf(direction) - pdf).abs() <= 1e-3 * pdf);
+            assert_eq!(map.radiance(direction), radiance);
+            if radiance.r > 1.0 {
+                bright += 1;
+            }
+        }
+        assert!(bright > n * 9 / 10);
+        // Uniform directions weighted by the pdf average to 1 / (4π) each.
+        let integral = (0..n)
+            .map(|_| {
+                let direction: [f32; 3] = UnitSphere.sample(&mut rng);
+                map.pdf(Vector3::from_array(direction)) * 4.0 * PI
+            })
+            .sum::<f32>()
+            / n as f32;
+        assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+    }
+}
@@ -1,30 +1,214 @@
 pub mod camera;
 use uuid::Uuid;
 pub mod color;
+pub mod environment;
+pub mod lights;
+pub mod material;
+pub mod obj;
+pub mod output;
+pub mod render;
+pub mod scene;
 pub mod shapes;
-pub use bvh::Point3;
+pub mod wire;
+pub use bvh::{aabb::AABB, Point3};
+use camera::Camera;
+use displaydoc::Display;
+use lights::Light;
+use scene::Background;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use shapes::Object;
-use displaydoc::Display;
+use std::time::Duration;
+
+/// How often slaves report to the controller that they are still alive.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long an idle slave waits before asking the controller for work again.
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of a world, derived from its contents so that jobs rendering the same
+/// world share it and slaves can tell whether they already have it. It is
+/// taken over the bincode encoding, so it doesn't depend on how the world
+/// travelled.
+pub fn world_hash(world: &[Object]) -> String {
+    sha256_hex(&wire::Encoding::Bincode.encode(world).unwrap())
+}
+
+/// Name of an environment map, taken over the image file as uploaded.
+pub fn environment_hash(data: &[u8]) -> String {
+    sha256_hex(data)
+}
 
-#[derive(Serialize, Deserialize, Display)]
-pub struct RenderInfo {
-    pub world: Vec<Object>,
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// pass {pass} of tile {tile_no}
+///
+/// The unit of work slaves claim from `/work/{slave}`.
+#[derive(Serialize, Deserialize, Display, Clone)]
+#[ignore_extra_doc_attributes]
+pub struct WorkItem {
     pub render_meta: RenderMeta,
-    pub division_no: u32,
+    /// Hash of the world to render, fetched from `/worlds/{hash}` by slaves
+    /// that don't have it yet.
+    pub world: String,
+    pub camera: Camera,
+    pub background: Background,
+    pub lights: Vec<Light>,
+    pub tile_no: u32,
+    pub pass: u32,
+    pub samples: u32,
 }
 
+/// One pass over a tile: the mean linear radiance of `samples` samples per
+/// pixel, as interleaved RGB rows of the tile.
 #[derive(Serialize, Deserialize)]
 pub struct ImageSlice {
-    pub division_no: u32,
-    pub image: Vec<u8>,
+    pub tile_no: u32,
+    pub pass: u32,
+    pub samples: u32,
+    pub image: Vec<f32>,
     pub id: Uuid,
+    /// The slave that rendered it.
+    pub slave: Uuid,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RenderMeta {
     pub height: u32,
     pub width: u32,
-    pub divisions: u32,
+    pub tile_size: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    pub seed: u64,
+    pub gamma: f32,
+    pub progressive: bool,
     pub id: Uuid,
 }
+
+/// A rectangle of the image, in pixels from the top left corner.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl RenderMeta {
+    pub fn tiles_x(&self) -> u32 {
+        self.width.div_ceil(self.tile_size)
+    }
+
+    pub fn tiles_y(&self) -> u32 {
+        self.height.div_ceil(self.tile_size)
+    }
+
+    /// Image sizes `RenderSettings::validate` lets through have far fewer
+    /// than `u32::MAX` tiles.
+    pub fn tile_count(&self) -> u32 {
+        self.tiles_x()
+            .checked_mul(self.tiles_y())
+            .expect("too many tiles for a valid image size")
+    }
+
+    /// Tiles are numbered row by row; the ones on the right and bottom edges
+    /// are cut short when the image size isn't a multiple of the tile size.
+    pub fn tile(&self, tile_no: u32) -> Tile {
+        let x = (tile_no % self.tiles_x()) * self.tile_size;
+        let y = (tile_no / self.tiles_x()) * self.tile_size;
+        Tile {
+            x,
+            y,
+            width: self.tile_size.min(self.width - x),
+            height: self.tile_size.min(self.height - y),
+        }
+    }
+
+    pub fn tile_at(&self, x: u32, y: u32) -> u32 {
+        (y / self.tile_size) * self.tiles_x() + x / self.tile_size
+    }
+
+    /// Sample counts of the passes every tile is rendered in. Progressive
+    /// jobs quadruple the running total each pass (1, 4, 16, 64, ...) so a
+    /// rough image is available early.
+    pub fn passes(&self) -> Vec<u32> {
+        if !self.progressive {
+            return vec![self.samples_per_pixel];
+        }
+        let mut passes = Vec::new();
+        let mut done = 0;
+        let mut target = 1u32;
+        while done < self.samples_per_pixel {
+            let total = target.min(self.samples_per_pixel);
+            passes.push(total - done);
+            done = total;
+            target = target.saturating_mul(4);
+        }
+        passes
+    }
+}
+
+/// The controller's answer to a heartbeat.
+#[derive(Serialize, Deserialize, Default)]
+pub struct HeartbeatReply {
+    /// Recently cancelled jobs; slaves abandon any work they have on them.
+    pub cancelled: Vec<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SlaveRegistration {
+    /// Base URL the controller can reach the slave on, e.g. `http://10.0.0.3:8081`.
+    pub address: String,
+    pub cores: u32,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn render_meta(width: u32, height: u32, tile_size: u32) -> RenderMeta {
+        RenderMeta {
+            height,
+            width,
+            tile_size,
+            samples_per_pixel: 1,
+            max_depth: 1,
+            seed: 0,
+            gamma: 2.2,
+            progressive: false,
+            id: Uuid::nil(),
+        }
+    }
+
+    #[test]
+    fn test_tiles_cover_every_pixel_once() {
+        for (width, height) in [(1921, 1081), (1920, 1080), (1, 1), (13, 200)] {
+            for tile_size in [64, 7, 1, 4096] {
+                let meta = render_meta(width, height, tile_size);
+                let mut covered = vec![0u8; width as usize * height as usize];
+                for tile_no in 0..meta.tile_count() {
+                    let tile = meta.tile(tile_no);
+                    assert!(tile.width > 0 && tile.height > 0);
+                    for y in tile.y..tile.y + tile.height {
+                        for x in tile.x..tile.x + tile.width {
+                            covered[(y * width + x) as usize] += 1;
+                            assert_eq!(meta.tile_at(x, y), tile_no);
+                        }
+                    }
+                }
+                assert!(
+                    covered.iter().all(|&count| count == 1),
+                    "{}x{} in tiles of {}",
+                    width,
+                    height,
+                    tile_size
+                );
+            }
+        }
+    }
+}
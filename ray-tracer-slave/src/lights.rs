@@ -0,0 +1,290 @@
+//! Lights for next-event estimation, so paths don't have to find small light
+//! sources by chance: emissive objects, and lights that exist only as such.
+use crate::{color::Color, shapes::Object, Point3};
+use bvh::Vector3;
+use rand::rngs::SmallRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// A light that isn't an object: rays never hit it, it only lights surfaces
+/// through shadow rays, so it shows neither on camera nor in mirrors.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Light {
+    /// Shines equally in all directions; `intensity` is per steradian.
+    Point {
+        position: Point3,
+        color: Color,
+        intensity: f32,
+    },
+    /// A point light restricted to a cone of half-angle `angle` degrees
+    /// around `direction`, fading out over the outer `blend` fraction of it.
+    Spot {
+        position: Point3,
+        direction: Vector3,
+        color: Color,
+        intensity: f32,
+        angle: f32,
+        #[serde(default)]
+        blend: f32,
+    },
+    /// Parallel light travelling along `direction`, like the sun;
+    /// `intensity` is the irradiance on a surface facing it.
+    Directional {
+        direction: Vector3,
+        color: Color,
+        intensity: f32,
+    },
+    /// Parallelogram spanned by `edge_a` and `edge_b` from `corner`, giving
+    /// off `intensity` radiance on the side `edge_a × edge_b` points to.
+    Area {
+        corner: Point3,
+        edge_a: Vector3,
+        edge_b: Vector3,
+        color: Color,
+        intensity: f32,
+        #[serde(default)]
+        two_sided: bool,
+    },
+}
+
+/// Light arriving at a point from a `Light`.
+pub struct Incident {
+    /// Towards the light.
+    pub direction: Vector3,
+    /// How far the light is, infinite for directional ones.
+    pub distance: f32,
+    /// Irradiance on a surface facing `direction`, divided by the density the
+    /// light's point was picked with.
+    pub irradiance: Color,
+}
+
+impl Light {
+    /// The light reaching `point`, not counting anything in the way.
+    pub fn illuminate(&self, point: Point3, rng: &mut SmallRng) -> Option<Incident> {
+        match *self {
+            Self::Point {
+                position,
+                color,
+                intensity,
+            } => towards(point, position, intensity * color),
+            Self::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                angle,
+                blend,
+            } => {
+                let incident = towards(point, position, intensity * color)?;
+                let cos = -incident.direction.dot(direction.try_normalize()?);
+                let cos_outer = angle.to_radians().cos();
+                let cos_inner = (angle * (1f32 - blend.clamp(0f32, 1f32)))
+                    .to_radians()
+                    .cos();
+                let falloff = if cos >= cos_inner {
+                    1f32
+                } else {
+                    let t = ((cos - cos_outer) / (cos_inner - cos_outer)).clamp(0f32, 1f32);
+                    t * t * (3f32 - 2f32 * t)
+                };
+                (falloff > 0f32).then(|| Incident {
+                    irradiance: incident.irradiance * falloff,
+                    ..incident
+                })
+            }
+            Self::Directional {
+                direction,
+                color,
+                intensity,
+            } => Some(Incident {
+                direction: -direction.try_normalize()?,
+                distance: f32::INFINITY,
+                irradiance: intensity * color,
+            }),
+            Self::Area {
+                corner,
+                edge_a,
+                edge_b,
+                color,
+                intensity,
+                two_sided,
+            } => {
+                let normal = edge_a.cross(edge_b);
+                let area = normal.length();
+                let normal = normal.try_normalize()?;
+                let position = corner + rng.gen::<f32>() * edge_a + rng.gen::<f32>() * edge_b;
+                let incident = towards(point, position, intensity * color)?;
+                let cos_light = -incident.direction.dot(normal);
+                let cos_light = if two_sided {
+                    cos_light.abs()
+                } else {
+                    cos_light
+                };
+                // Picked with density 1 / area, and seen at an angle.
+                (cos_light > 0f32).then(|| Incident {
+                    irradiance: incident.irradiance * (cos_light * area),
+                    ..incident
+                })
+            }
+        }
+    }
+}
+
+/// Light from `position` falling off with the square of the distance.
+fn towards(point: Point3, position: Point3, intensity: Color) -> Option<Incident> {
+    let offset = position - point;
+    let distance = offset.length();
+    (distance > 0f32).then(|| Incident {
+        direction: offset / distance,
+        distance,
+        irradiance: intensity / (distance * distance),
+    })
+}
+
+/// A point picked on a light.
+pub struct LightSample {
+    pub point: Point3,
+    pub normal: Vector3,
+    pub emitted: Color,
+    /// Density the point was picked with, per unit area.
+    pub pdf: f32,
+}
+
+/// The emissive objects of a world. Each is picked in proportion to the power
+/// it gives off and a point on it uniformly by area, so the density of a point
+/// only depends on how bright the surface there is.
+#[derive(Default)]
+pub struct LightList {
+    /// Indices of the emissive objects in the world, along with the part of
+    /// them that is emissive, as the triangles of meshes are picked one by
+    /// one.
+    objects: Vec<(usize, Option<usize>)>,
+    /// Running total of their power.
+    cdf: Vec<f32>,
+}
+
+impl LightList {
+    pub fn new(world: &[Object]) -> Self {
+        let mut lights = Self::default();
+        let mut total = 0f32;
+        for (i, object) in world.iter().enumerate() {
+            for part in object.parts() {
+                let power = object.material_at(part, Point3::ZERO).emitted().luminance()
+                    * object.area(part);
+                if power > 0f32 {
+                    total += power;
+                    lights.objects.push((i, part));
+                    lights.cdf.push(total);
+                }
+            }
+        }
+        lights
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    fn total_power(&self) -> f32 {
+        self.cdf.last().copied().unwrap_or_default()
+    }
+
+    pub fn sample(&self, world: &[Object], rng: &mut SmallRng) -> Option<LightSample> {
+        let target = rng.gen::<f32>() * self.total_power();
+        let idx = self
+            .cdf
+            .partition_point(|power| *power <= target)
+            .min(self.objects.len().checked_sub(1)?);
+        let (object, part) = self.objects[idx];
+        let object = &world[object];
+        let (point, normal) = object.sample_point(part, rng);
+        let emitted = object.material_at(part, point).emitted();
+        Some(LightSample {
+            point,
+            normal,
+            emitted,
+            pdf: self.pdf(emitted),
+        })
+    }
+
+    /// Density, per unit area, of `sample` picking a point on a surface that
+    /// emits `emitted`.
+    pub fn pdf(&self, emitted: Color) -> f32 {
+        if self.is_empty() {
+            return 0f32;
+        }
+        emitted.luminance() / self.total_power()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_point_and_spot() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let position = Point3::new(0.0, 2.0, 0.0);
+        let point = Light::Point {
+            position,
+            color: color::WHITE,
+            intensity: 8.0,
+        };
+        let incident = point.illuminate(Point3::ZERO, &mut rng).unwrap();
+        assert_eq!(incident.direction, Vector3::Y);
+        assert_eq!(incident.distance, 2.0);
+        assert_eq!(incident.irradiance, 2.0 * color::WHITE);
+
+        let spot = Light::Spot {
+            position,
+            direction: -Vector3::Y,
+            color: color::WHITE,
+            intensity: 8.0,
+            angle: 30.0,
+            blend: 0.5,
+        };
+        let at = |x: f32, rng: &mut SmallRng| {
+            spot.illuminate(Point3::new(x, 0.0, 0.0), rng)
+                .map(|incident| incident.irradiance.r * incident.distance.powi(2))
+        };
+        // Full strength within 15 degrees, nothing beyond 30.
+        assert_eq!(at(0.0, &mut rng), Some(8.0));
+        assert_eq!(at(2.0 * 10f32.to_radians().tan(), &mut rng), Some(8.0));
+        let blended = at(2.0 * 22f32.to_radians().tan(), &mut rng).unwrap();
+        assert!(blended > 0.0 && blended < 8.0);
+        assert!(at(2.0 * 35f32.to_radians().tan(), &mut rng).is_none());
+    }
+
+    /// Far away, an area light looks like a point light with an intensity of
+    /// its radiance times its area.
+    #[test]
+    fn test_area_light_far_away() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        let light = Light::Area {
+            corner: Point3::new(-0.5, 100.0, -0.5),
+            edge_a: Vector3::X,
+            edge_b: Vector3::Z,
+            color: color::WHITE,
+            intensity: 3.0,
+            two_sided: false,
+        };
+        let n = 1000;
+        let mean = (0..n)
+            .map(|_| {
+                light
+                    .illuminate(Point3::ZERO, &mut rng)
+                    .unwrap()
+                    .irradiance
+                    .r
+            })
+            .sum::<f32>()
+            / n as f32;
+        assert!((mean * 100f32 * 100f32 - 3.0).abs() < 1e-3);
+        // It only shines downwards.
+        assert!(light
+            .illuminate(Point3::new(0.0, 200.0, 0.0), &mut rng)
+            .is_none());
+    }
+}
@@ -1,174 +1,315 @@
-use actix_web::{post, web, App, HttpServer, Responder};
-use bvh::bvh::BVH;
-use bvh::ray::Ray;
-use bvh::{Point3, Vector3};
-use crossbeam_channel::unbounded;
-use crossbeam_channel::{Receiver, Sender};
-use log::info;
-use rand::rngs::SmallRng;
-use rand::SeedableRng;
-use rand_distr::{Distribution, UnitSphere};
+use log::{info, warn};
 use ray_tracer_interface::ImageSlice;
 use ray_tracer_interface::{
-    camera,
-    color::{self, Color},
-    shapes::{WorldList, WorldRefList},
-    RenderInfo,
+    environment::EnvironmentMap,
+    environment_hash,
+    render::{render_pass, World, WorldData},
+    wire::Encoding,
+    HeartbeatReply, SlaveRegistration, WorkItem, HEARTBEAT_INTERVAL, IDLE_POLL_INTERVAL,
 };
-use rayon::prelude::{IndexedParallelIterator, ParallelIterator};
-use rayon::slice::ParallelSliceMut;
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
 use serde_json::json;
-use std::f32::consts::PI;
+use std::collections::HashSet;
+use std::error::Error;
+use std::net::UdpSocket;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
 
-enum MessageToWorker {
-    NewJob(RenderInfo),
+/// The id the master knows us by, once registered.
+type SlaveId = Arc<RwLock<Option<Uuid>>>;
+/// Jobs the master told us were cancelled.
+type Cancelled = Arc<RwLock<HashSet<Uuid>>>;
+
+/// How we talk to the master, overridable through `WIRE_ENCODING` (`json`,
+/// `bincode` or `zstd`).
+fn wire_encoding() -> Encoding {
+    std::env::var("WIRE_ENCODING")
+        .ok()
+        .and_then(|name| {
+            name.parse()
+                .map_err(|e| warn!("ignoring WIRE_ENCODING: {}", e))
+                .ok()
+        })
+        .unwrap_or(Encoding::BincodeZstd)
+}
+
+/// Decodes a response in whatever encoding the master chose for it.
+fn decode<T: DeserializeOwned>(response: Response) -> Result<T, Box<dyn Error>> {
+    let encoding = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(Encoding::from_content_type)
+        .unwrap_or(Ok(Encoding::Json))?;
+    Ok(encoding.decode(&response.bytes()?)?)
+}
+
+fn master_url() -> String {
+    std::env::var("MASTER_URL").unwrap_or_else(|_| "http://master:8080".to_owned())
 }
 
-struct AppState {
-    tx: Sender<MessageToWorker>,
+/// The address we announce to the master, which tells a restarted slave apart
+/// from a new one. Unless overridden, it is the IP of whichever interface
+/// routes to the master.
+fn own_address(master: &str) -> String {
+    if let Ok(address) = std::env::var("SLAVE_ADDRESS") {
+        return address;
+    }
+    let host = master
+        .split("://")
+        .last()
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or_default();
+    UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect(host)?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|e| {
+            warn!("could not work out own address: {}", e);
+            "slave".to_owned()
+        })
 }
 
-fn worker(rx: Receiver<MessageToWorker>) {
+fn heartbeat(master: String, slave_id: SlaveId, cancelled: Cancelled) {
     let client = Client::new();
+    let registration = SlaveRegistration {
+        address: own_address(&master),
+        cores: std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1),
+    };
     loop {
-        if let Ok(message) = rx.recv() {
-            match message {
-                MessageToWorker::NewJob(mut req) => {
-                    info!("Got job");
-                    let max_bounces = 10;
-                    let image_height = req.render_meta.height;
-                    let image_width = req.render_meta.width;
-                    let camera = camera::Camera::new(
-                        Point3::ZERO,
-                        image_width as f32 / image_height as f32,
-                        0.1f32,
-                        1f32,
-                        PI / 2f32,
-                        1f32,
-                        image_height as f32,
-                    );
-                    let sample_count: u32 = 100;
-
-                    let mut img_buff = vec![
-                        0u8;
-                        (image_height as usize
-                            / req.render_meta.divisions as usize)
-                            * image_width as usize
-                            * 3
-                    ];
-                    let bvh = BVH::build(&mut req.world);
-                    let world = WorldList::from_vec(req.world);
-                    img_buff
-                        .par_chunks_exact_mut(image_width as usize * 3)
-                        .enumerate()
-                        .for_each(|(y, row)| {
-                            let y = ((image_height / req.render_meta.divisions) * req.division_no)
-                                as usize
-                                + y;
-                            let mut rng = SmallRng::from_entropy();
-                            for (x, p) in row.chunks_exact_mut(3).enumerate() {
-                                let y = image_height as usize - y - 1;
-                                let mut pix_color = color::BLACK;
-                                for _ in 0..sample_count {
-                                    let r = camera.get_ray(x as u32, y as u32, &mut rng);
-                                    pix_color +=
-                                        ray_color(&r, &world, max_bounces + 1, &mut rng, &bvh);
-                                }
-                                pix_color.r = (pix_color.r / sample_count as f32).sqrt();
-                                pix_color.g = (pix_color.g / sample_count as f32).sqrt();
-                                pix_color.b = (pix_color.b / sample_count as f32).sqrt();
-                                [p[0], p[1], p[2]] = pix_color.as_slice();
-                            }
-                        });
-                    info!("render finished");
-                    let p = json!(ImageSlice {
-                        id: req.render_meta.id,
-                        image: img_buff,
-                        division_no: req.division_no
-                    })
-                    .to_string();
+        let id = *slave_id.read().unwrap();
+        let id = match id {
+            None => client
+                .post(format!("{}/register", master))
+                .body(json!(registration).to_string())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .send()
+                .and_then(|response| response.error_for_status())
+                .and_then(|response| response.text())
+                .map_err(|e| warn!("registration failed: {}", e))
+                .ok()
+                .and_then(|id| Uuid::parse_str(&id).ok())
+                .inspect(|id| {
                     info!(
-                        "master responded to result:  {}",
-                        client
-                            .post("http://master:8080/result")
-                            .body(p,)
-                            .header(reqwest::header::CONTENT_TYPE, "application/json")
-                            .send()
-                            .unwrap()
-                            .text()
-                            .unwrap_or_default()
-                    );
+                        "registered with master as {} ({})",
+                        id, registration.address
+                    )
+                }),
+            Some(id) => match client.post(format!("{}/heartbeat/{}", master, id)).send() {
+                Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                    info!("master forgot about us, registering again");
+                    None
+                }
+                Ok(response) => {
+                    match response.json::<HeartbeatReply>() {
+                        Ok(reply) => {
+                            *cancelled.write().unwrap() = reply.cancelled.into_iter().collect()
+                        }
+                        Err(e) => warn!("bad heartbeat reply: {}", e),
+                    }
+                    Some(id)
+                }
+                Err(e) => {
+                    warn!("heartbeat failed: {}", e);
+                    Some(id)
                 }
+            },
+        };
+        *slave_id.write().unwrap() = id;
+        std::thread::sleep(HEARTBEAT_INTERVAL);
+    }
+}
+
+/// How many worlds to keep around, overridable through `WORLD_CACHE_SIZE`.
+fn world_cache_size() -> usize {
+    std::env::var("WORLD_CACHE_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(4)
+}
+
+/// How many environment maps to keep around, overridable through
+/// `ENVIRONMENT_CACHE_SIZE`.
+fn environment_cache_size() -> usize {
+    std::env::var("ENVIRONMENT_CACHE_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(2)
+}
+
+/// Things we rendered with recently, by hash and most recently used first, so
+/// consecutive jobs on the same world or environment map skip the download
+/// and the preprocessing.
+struct Cache<T> {
+    entries: Vec<(String, T)>,
+    capacity: usize,
+}
+
+impl<T> Cache<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn get(&mut self, hash: &str, fetch: impl FnOnce() -> Option<T>) -> Option<&T> {
+        match self.entries.iter().position(|(cached, _)| cached == hash) {
+            Some(idx) => {
+                let entry = self.entries.remove(idx);
+                self.entries.insert(0, entry);
+            }
+            None => {
+                self.entries.insert(0, (hash.to_owned(), fetch()?));
+                self.entries.truncate(self.capacity);
             }
         }
+        self.entries.first().map(|(_, value)| value)
+    }
+}
+
+fn fetch_world(client: &Client, master: &str, encoding: Encoding, hash: &str) -> Option<World> {
+    let response = client
+        .get(format!("{}/worlds/{}", master, hash))
+        .header(reqwest::header::ACCEPT, encoding.content_type())
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| warn!("fetching world {} failed: {}", hash, e))
+        .ok()?;
+    let world: WorldData = decode(response)
+        .map_err(|e| warn!("bad world {}: {}", hash, e))
+        .ok()?;
+    if world.hash() != hash {
+        warn!("world {} arrived corrupted", hash);
+        return None;
     }
+    info!("Got world {} with {} objects", hash, world.objects.len());
+    Some(World::prebuilt(world).unwrap_or_else(|world| {
+        warn!("world {} came with a broken BVH, building our own", hash);
+        World::new(world.objects)
+    }))
 }
 
-fn ray_color(ray: &Ray, world: &WorldList, depth: u32, rng: &mut SmallRng, bvh: &BVH) -> Color {
-    if depth == 0 {
-        return color::BLACK;
+/// Environment maps travel as the image file they were uploaded as.
+fn fetch_environment(client: &Client, master: &str, hash: &str) -> Option<EnvironmentMap> {
+    let data = client
+        .get(format!("{}/environments/{}", master, hash))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .map_err(|e| warn!("fetching environment map {} failed: {}", hash, e))
+        .ok()?;
+    if environment_hash(&data) != hash {
+        warn!("environment map {} arrived corrupted", hash);
+        return None;
     }
-    let v = world.get();
-    let world_sub = bvh.traverse(ray, &v);
-    match WorldRefList::from_vec(world_sub).intersect(&ray) {
-        Some(table) => {
-            if table.emission > 0f32 {
-                table.emission * table.albedo
-            } else {
-                let diffuse_dir = Vector3::from_slice(&UnitSphere.sample(rng)) + table.normal;
-                let glossy_dir =
-                    ray.direction - 2f32 * ray.direction.dot(table.normal) * table.normal;
-                let scatter_direction = diffuse_dir + table.roughness * (glossy_dir - diffuse_dir);
-                table.albedo.blend(&ray_color(
-                    &Ray::new(
-                        table.point,
-                        scatter_direction.try_normalize().unwrap_or(table.normal),
-                    ),
-                    world,
-                    depth - 1,
-                    rng,
-                    bvh,
-                ))
+    let map = EnvironmentMap::decode(&data)
+        .map_err(|e| warn!("bad environment map {}: {}", hash, e))
+        .ok()?;
+    info!("Got environment map {}", hash);
+    Some(map)
+}
+
+/// Asks the master for work whenever idle, so faster machines simply end up
+/// asking more often.
+fn worker(master: String, slave_id: SlaveId, cancelled: Cancelled) {
+    let client = Client::new();
+    let encoding = wire_encoding();
+    let mut worlds = Cache::new(world_cache_size());
+    let mut environments = Cache::new(environment_cache_size());
+    loop {
+        let Some(id) = *slave_id.read().unwrap() else {
+            std::thread::sleep(IDLE_POLL_INTERVAL);
+            continue;
+        };
+        let work: WorkItem = match client
+            .post(format!("{}/work/{}", master, id))
+            .header(reqwest::header::ACCEPT, encoding.content_type())
+            .send()
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) if response.status() == StatusCode::NO_CONTENT => {
+                std::thread::sleep(IDLE_POLL_INTERVAL);
+                continue;
             }
-        }
-        None => {
-            let t = ray.direction.normalize_or_zero().y * 0.5 + 1f32;
-            t * color::WHITE
-                + (1f32 - t)
-                    * Color {
-                        r: 0.3,
-                        g: 0.3,
-                        b: 0.8f32,
+            Ok(response) => match decode(response) {
+                Ok(work) => work,
+                Err(e) => {
+                    warn!("bad work item: {}", e);
+                    std::thread::sleep(IDLE_POLL_INTERVAL);
+                    continue;
+                }
+            },
+            Err(e) => {
+                warn!("asking for work failed: {}", e);
+                std::thread::sleep(IDLE_POLL_INTERVAL);
+                continue;
+            }
+        };
+        let job_id = work.render_meta.id;
+        let Some(world) = worlds.get(&work.world, || {
+            fetch_world(&client, &master, encoding, &work.world)
+        }) else {
+            // The tile goes back to the queue once its assignment times out.
+            std::thread::sleep(IDLE_POLL_INTERVAL);
+            continue;
+        };
+        let environment = match work.background.environment_map() {
+            Some(hash) => {
+                match environments.get(hash, || fetch_environment(&client, &master, hash)) {
+                    Some(map) => Some(map),
+                    None => {
+                        std::thread::sleep(IDLE_POLL_INTERVAL);
+                        continue;
                     }
+                }
+            }
+            None => None,
+        };
+        info!("rendering {} of {}", work, job_id);
+        let Some(image) = render_pass(&work, world, environment, || {
+            cancelled.read().unwrap().contains(&job_id)
+        }) else {
+            info!("job {} was cancelled", job_id);
+            continue;
+        };
+        let p = encoding
+            .encode(&ImageSlice {
+                id: job_id,
+                image,
+                tile_no: work.tile_no,
+                pass: work.pass,
+                samples: work.samples,
+                slave: id,
+            })
+            .unwrap();
+        match client
+            .post(format!("{}/result", master))
+            .body(p)
+            .header(reqwest::header::CONTENT_TYPE, encoding.content_type())
+            .send()
+            .and_then(|response| response.text())
+        {
+            Ok(text) => info!("master responded to result:  {}", text),
+            Err(e) => warn!("sending result failed: {}", e),
         }
     }
 }
 
-#[post("/")]
-async fn index(req: web::Json<RenderInfo>, state: web::Data<AppState>) -> impl Responder {
-    info!("Slave Got request");
-    let req = req.into_inner();
-    state.tx.send(MessageToWorker::NewJob(req)).unwrap();
-    "i'll get you a slice at once"
-}
-
-#[actix_web::main]
-async fn main() {
+fn main() {
     pretty_env_logger::init();
-    let (tx, rx) = unbounded::<MessageToWorker>();
-    std::thread::spawn(|| worker(rx));
-    let state = web::Data::new(AppState { tx });
-
-    HttpServer::new(move || {
-        App::new()
-            .service(index)
-            .app_data(state.clone())
-            .app_data(web::JsonConfig::default().limit(500_000_000))
-    })
-    .bind(("0.0.0.0", 8081))
-    .unwrap()
-    .run()
-    .await
-    .unwrap();
+    let master = master_url();
+    let slave_id = SlaveId::default();
+    let heartbeat_master = master.clone();
+    let heartbeat_id = slave_id.clone();
+    let cancelled = Cancelled::default();
+    let heartbeat_cancelled = cancelled.clone();
+    std::thread::spawn(move || heartbeat(heartbeat_master, heartbeat_id, heartbeat_cancelled));
+    worker(master, slave_id, cancelled);
 }
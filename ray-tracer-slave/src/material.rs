@@ -0,0 +1,412 @@
+//! Surface materials and their BSDFs. Directions are unit vectors pointing
+//! away from the surface: `wo` towards where the light goes, `wi` towards
+//! where it comes from, and `normal` is on the side `wo` is on. Only
+//! dielectrics let light through to the other side.
+use crate::color::{self, Color};
+use bvh::Vector3;
+use rand::rngs::SmallRng;
+use rand::Rng;
+use rand_distr::{Distribution, UnitSphere};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// Roughness below this makes the GGX lobe too sharp for `f32`; such
+/// surfaces are as good as mirrors anyway.
+const MIN_ALPHA: f32 = 1e-3;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Material {
+    /// Ideal diffuse reflector.
+    Lambertian { albedo: Color },
+    /// Conductor with a GGX microfacet distribution; `albedo` is its
+    /// reflectance at normal incidence.
+    Metal { albedo: Color, roughness: f32 },
+    /// Light source that absorbs whatever hits it.
+    Emissive { color: Color, strength: f32 },
+    /// Smooth boundary of a transparent medium such as glass (`ior` 1.5) or
+    /// water (1.33), seen from the outside. Transmitted light is filtered by
+    /// `tint` each time it passes through.
+    Dielectric {
+        ior: f32,
+        #[serde(default = "default_tint")]
+        tint: Color,
+    },
+    /// Disney-style blend of a diffuse base and a GGX specular layer, from
+    /// plastic (`metallic` 0) to metal (`metallic` 1).
+    Principled {
+        base_color: Color,
+        #[serde(default)]
+        metallic: f32,
+        #[serde(default = "default_roughness")]
+        roughness: f32,
+        /// Scales the reflectance of non-metals; 0.5 is 4%, typical of most.
+        #[serde(default = "default_specular")]
+        specular: f32,
+    },
+}
+
+fn default_roughness() -> f32 {
+    0.5
+}
+
+fn default_specular() -> f32 {
+    0.5
+}
+
+fn default_tint() -> Color {
+    color::WHITE
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self::Lambertian {
+            albedo: Color::from_slice([0.5, 0.5, 0.5]),
+        }
+    }
+}
+
+/// A direction picked by `Material::sample`.
+pub struct BsdfSample {
+    pub direction: Vector3,
+    /// BSDF times cosine over pdf: what the light arriving from `direction`
+    /// is multiplied by.
+    pub weight: Color,
+    /// Density `direction` was picked with, or for specular samples the
+    /// probability of the reflection or refraction taken.
+    pub pdf: f32,
+    /// Perfect reflection or refraction, which `eval` and `pdf` know nothing
+    /// about.
+    pub specular: bool,
+}
+
+impl Material {
+    pub fn emitted(&self) -> Color {
+        match self {
+            Self::Emissive { color, strength } => *strength * *color,
+            _ => color::BLACK,
+        }
+    }
+
+    /// Picks a direction for light to arrive from, or nothing if the path
+    /// ends here. `front_face` tells whether `wo` is outside the surface.
+    pub fn sample(
+        &self,
+        wo: Vector3,
+        normal: Vector3,
+        front_face: bool,
+        rng: &mut SmallRng,
+    ) -> Option<BsdfSample> {
+        let wi = match self {
+            Self::Emissive { .. } => return None,
+            Self::Lambertian { albedo } => {
+                let wi = sample_cosine(normal, rng);
+                return Some(BsdfSample {
+                    direction: wi,
+                    weight: *albedo,
+                    pdf: wi.dot(normal) / PI,
+                    specular: false,
+                });
+            }
+            Self::Dielectric { ior, tint } => {
+                let eta = if front_face { 1f32 / ior } else { *ior };
+                return Some(sample_dielectric(wo, normal, eta, *tint, rng));
+            }
+            Self::Metal { roughness, .. } => sample_ggx(wo, normal, alpha(*roughness), rng),
+            Self::Principled {
+                metallic,
+                roughness,
+                ..
+            } => {
+                if rng.gen::<f32>() < specular_probability(*metallic) {
+                    sample_ggx(wo, normal, alpha(*roughness), rng)
+                } else {
+                    sample_cosine(normal, rng)
+                }
+            }
+        };
+        let pdf = self.pdf(wo, wi, normal);
+        if wi.dot(normal) <= 0f32 || pdf <= 0f32 {
+            return None;
+        }
+        Some(BsdfSample {
+            direction: wi,
+            weight: self.eval(wo, wi, normal) / pdf,
+            pdf,
+            specular: false,
+        })
+    }
+
+    /// The BSDF times the cosine of `wi` with the normal.
+    pub fn eval(&self, wo: Vector3, wi: Vector3, normal: Vector3) -> Color {
+        let cos_o = wo.dot(normal);
+        let cos_i = wi.dot(normal);
+        if cos_o <= 0f32 || cos_i <= 0f32 {
+            return color::BLACK;
+        }
+        match self {
+            Self::Emissive { .. } | Self::Dielectric { .. } => color::BLACK,
+            Self::Lambertian { albedo } => *albedo * (cos_i / PI),
+            Self::Metal { albedo, roughness } => ggx(wo, wi, normal, alpha(*roughness), *albedo),
+            Self::Principled {
+                base_color,
+                metallic,
+                roughness,
+                specular,
+            } => {
+                let f0 =
+                    (1f32 - metallic) * (0.08 * specular) * color::WHITE + *metallic * *base_color;
+                let h = (wo + wi).normalize();
+                // Burley's diffuse, which darkens grazing angles on smooth
+                // surfaces and brightens them on rough ones.
+                let cos_d = wi.dot(h);
+                let fd90 = 0.5 + 2f32 * roughness * cos_d * cos_d;
+                let retro = (1f32 + (fd90 - 1f32) * (1f32 - cos_i).powi(5))
+                    * (1f32 + (fd90 - 1f32) * (1f32 - cos_o).powi(5));
+                let diffuse = *base_color * ((1f32 - metallic) * retro * cos_i / PI);
+                diffuse + ggx(wo, wi, normal, alpha(*roughness), f0)
+            }
+        }
+    }
+
+    /// Density `sample` picks `wi` with, per solid angle.
+    pub fn pdf(&self, wo: Vector3, wi: Vector3, normal: Vector3) -> f32 {
+        let cos_i = wi.dot(normal);
+        if wo.dot(normal) <= 0f32 || cos_i <= 0f32 {
+            return 0f32;
+        }
+        match self {
+            Self::Emissive { .. } | Self::Dielectric { .. } => 0f32,
+            Self::Lambertian { .. } => cos_i / PI,
+            Self::Metal { roughness, .. } => ggx_pdf(wo, wi, normal, alpha(*roughness)),
+            Self::Principled {
+                metallic,
+                roughness,
+                ..
+            } => {
+                let p = specular_probability(*metallic);
+                p * ggx_pdf(wo, wi, normal, alpha(*roughness)) + (1f32 - p) * cos_i / PI
+            }
+        }
+    }
+}
+
+fn alpha(roughness: f32) -> f32 {
+    (roughness * roughness).max(MIN_ALPHA)
+}
+
+/// How often the principled BSDF samples its specular lobe; metals have
+/// nothing else.
+fn specular_probability(metallic: f32) -> f32 {
+    1f32 / (2f32 - metallic.clamp(0f32, 1f32))
+}
+
+fn schlick(f0: Color, cos: f32) -> Color {
+    f0 + (1f32 - cos).clamp(0f32, 1f32).powi(5) * (color::WHITE - f0)
+}
+
+/// Share of light a smooth dielectric reflects, for unpolarized light
+/// arriving at `cos_i` and leaving at `cos_t` on the other side, where `eta`
+/// is the ratio of the indices of refraction of the two sides.
+fn fresnel(cos_i: f32, cos_t: f32, eta: f32) -> f32 {
+    let parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2f32
+}
+
+/// Reflects or refracts with the Fresnel reflectance as probability, so the
+/// weight is one apart from the tint. `eta` is the index of refraction on the
+/// side of `wo` over that of the other side.
+fn sample_dielectric(
+    wo: Vector3,
+    normal: Vector3,
+    eta: f32,
+    tint: Color,
+    rng: &mut SmallRng,
+) -> BsdfSample {
+    let cos_o = wo.dot(normal);
+    let sin2_t = eta * eta * (1f32 - cos_o * cos_o);
+    // Beyond the critical angle everything is reflected.
+    let reflectance = if sin2_t >= 1f32 {
+        1f32
+    } else {
+        fresnel(cos_o, (1f32 - sin2_t).sqrt(), eta)
+    };
+    if rng.gen::<f32>() < reflectance {
+        BsdfSample {
+            direction: 2f32 * cos_o * normal - wo,
+            weight: color::WHITE,
+            pdf: reflectance,
+            specular: true,
+        }
+    } else {
+        let cos_t = (1f32 - sin2_t).sqrt();
+        BsdfSample {
+            direction: (-eta * wo + (eta * cos_o - cos_t) * normal).normalize(),
+            weight: tint,
+            pdf: 1f32 - reflectance,
+            specular: true,
+        }
+    }
+}
+
+/// Cosine weighted direction around `normal`.
+fn sample_cosine(normal: Vector3, rng: &mut SmallRng) -> Vector3 {
+    (normal + Vector3::from_slice(&UnitSphere.sample(rng)))
+        .try_normalize()
+        .unwrap_or(normal)
+}
+
+fn ggx_d(cos_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = cos_h * cos_h * (a2 - 1f32) + 1f32;
+    a2 / (PI * d * d)
+}
+
+/// Smith's shadowing term for one direction.
+fn ggx_g1(cos: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    2f32 * cos / (cos + (a2 + (1f32 - a2) * cos * cos).sqrt())
+}
+
+/// GGX specular reflection times the cosine of `wi`, with Schlick's Fresnel.
+fn ggx(wo: Vector3, wi: Vector3, normal: Vector3, alpha: f32, f0: Color) -> Color {
+    let h = (wo + wi).normalize();
+    let cos_o = wo.dot(normal);
+    let cos_i = wi.dot(normal);
+    let d = ggx_d(h.dot(normal), alpha);
+    let g = ggx_g1(cos_o, alpha) * ggx_g1(cos_i, alpha);
+    schlick(f0, wo.dot(h)) * (d * g / (4f32 * cos_o))
+}
+
+fn ggx_pdf(wo: Vector3, wi: Vector3, normal: Vector3, alpha: f32) -> f32 {
+    let h = (wo + wi).normalize();
+    ggx_d(h.dot(normal), alpha) * h.dot(normal) / (4f32 * wo.dot(h).abs())
+}
+
+/// Mirrors `wo` about a microfacet normal drawn from the GGX distribution.
+fn sample_ggx(wo: Vector3, normal: Vector3, alpha: f32, rng: &mut SmallRng) -> Vector3 {
+    let (u, v): (f32, f32) = (rng.gen(), rng.gen());
+    let cos_theta = ((1f32 - u) / (1f32 + (alpha * alpha - 1f32) * u)).sqrt();
+    let sin_theta = (1f32 - cos_theta * cos_theta).max(0f32).sqrt();
+    let phi = 2f32 * PI * v;
+    let (tangent, bitangent) = normal.any_orthonormal_pair();
+    let h =
+        sin_theta * phi.cos() * tangent + sin_theta * phi.sin() * bitangent + cos_theta * normal;
+    2f32 * wo.dot(h) * h - wo
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn materials() -> Vec<Material> {
+        let albedo = Color::from_slice([0.9, 0.6, 0.3]);
+        vec![
+            Material::Lambertian { albedo },
+            Material::Metal {
+                albedo,
+                roughness: 0.4,
+            },
+            Material::Principled {
+                base_color: albedo,
+                metallic: 0.3,
+                roughness: 0.6,
+                specular: 0.5,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_sample_matches_eval_and_pdf() {
+        let mut rng = SmallRng::seed_from_u64(7);
+        let normal = Vector3::new(0.0, 0.0, 1.0);
+        let wo = Vector3::new(0.3, -0.2, 0.8).normalize();
+        for material in materials() {
+            for _ in 0..1000 {
+                let Some(sample) = material.sample(wo, normal, true, &mut rng) else {
+                    continue;
+                };
+                let pdf = material.pdf(wo, sample.direction, normal);
+                let expected = material.eval(wo, sample.direction, normal) / pdf;
+                assert!((sample.pdf - pdf).abs() <= 1e-4 * pdf, "{:?}", material);
+                assert!((sample.weight - expected).r.abs() <= 1e-4 * expected.r.max(1.0));
+            }
+        }
+    }
+
+    /// Estimates the reflected fraction of uniform light, which can't exceed
+    /// the albedo.
+    #[test]
+    fn test_energy_conservation() {
+        let mut rng = SmallRng::seed_from_u64(11);
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        for wo in [normal, Vector3::new(0.8, 0.6, 0.0)] {
+            for material in materials() {
+                let n = 20000;
+                let mut sum = color::BLACK;
+                for _ in 0..n {
+                    if let Some(sample) = material.sample(wo, normal, true, &mut rng) {
+                        sum += sample.weight;
+                    }
+                }
+                let mean = sum / n as f32;
+                assert!(mean.r <= 1.0, "{:?} reflects {:?}", material, mean);
+                assert!(mean.r > 0.3, "{:?} reflects {:?}", material, mean);
+            }
+        }
+    }
+
+    #[test]
+    fn test_emissive() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let light = Material::Emissive {
+            color: color::WHITE,
+            strength: 4.0,
+        };
+        assert_eq!(light.emitted(), 4.0 * color::WHITE);
+        assert!(light
+            .sample(Vector3::Z, Vector3::Z, true, &mut rng)
+            .is_none());
+    }
+
+    #[test]
+    fn test_dielectric() {
+        let mut rng = SmallRng::seed_from_u64(5);
+        let glass = Material::Dielectric {
+            ior: 1.5,
+            tint: color::WHITE,
+        };
+        let normal = Vector3::Z;
+        let reflected = |wo: Vector3, front_face, rng: &mut SmallRng| {
+            let samples: Vec<_> = (0..10000)
+                .map(|_| glass.sample(wo, normal, front_face, rng).unwrap())
+                .collect();
+            for sample in &samples {
+                assert!(sample.specular);
+                assert_eq!(sample.weight, color::WHITE);
+            }
+            samples
+                .iter()
+                .filter(|sample| sample.direction.dot(normal) > 0f32)
+                .count() as f32
+                / samples.len() as f32
+        };
+        // About 4% is reflected head on, from either side.
+        assert!((reflected(normal, true, &mut rng) - 0.04).abs() < 0.01);
+        assert!((reflected(normal, false, &mut rng) - 0.04).abs() < 0.01);
+        // Past the critical angle of about 42 degrees light can't get out.
+        let grazing = Vector3::new(0.8, 0.0, 0.6);
+        assert_eq!(reflected(grazing, false, &mut rng), 1f32);
+        assert!(reflected(grazing, true, &mut rng) < 0.2);
+
+        // Snell's law going in.
+        let wo = Vector3::new(0.5, 0.0, 0.75f32.sqrt());
+        let refracted = (0..100)
+            .map(|_| glass.sample(wo, normal, true, &mut rng).unwrap())
+            .find(|sample| sample.direction.dot(normal) < 0f32)
+            .unwrap();
+        let sin_t = refracted.direction.cross(-normal).length();
+        assert!((sin_t - 0.5 / 1.5).abs() < 1e-5);
+    }
+}
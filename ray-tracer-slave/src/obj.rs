@@ -0,0 +1,104 @@
+use std::io::BufReader;
+
+use crate::{
+    color::Color,
+    material::Material,
+    shapes::{
+        mesh::{Mesh, MeshError},
+        Object,
+    },
+    Point3,
+};
+use bvh::Vector3;
+use displaydoc::Display;
+use log::info;
+
+#[derive(Debug, Display)]
+pub enum ObjError {
+    /// failed to load OBJ file: {0}
+    Load(tobj::LoadError),
+    /// model `{0}` has no material
+    NoMaterial(String),
+    /// {0}
+    Mesh(MeshError),
+}
+
+impl std::error::Error for ObjError {}
+
+/// Builds a world from an OBJ file and the MTL file its materials come from.
+/// The whole file becomes a single mesh, with the MTL file as its material
+/// table.
+pub fn build_world(obj: &[u8], mtl: &[u8]) -> Result<Vec<Object>, ObjError> {
+    let mut obj_br = BufReader::new(obj);
+    info!("Retrieving models and materials");
+    let options = tobj::LoadOptions {
+        single_index: true,
+        ..Default::default()
+    };
+    let (models, materials) = tobj::load_obj_buf(&mut obj_br, &options, |_| {
+        tobj::load_mtl_buf(&mut BufReader::new(mtl))
+    })
+    .map_err(ObjError::Load)?;
+    // Phong exponents map to roughly the same highlight as a GGX alpha of
+    // sqrt(2 / (Ns + 2)), and alpha is roughness squared.
+    let materials = materials
+        .map_err(ObjError::Load)?
+        .iter()
+        .map(|material| Material::Principled {
+            base_color: Color::from_slice(material.diffuse),
+            metallic: 0f32,
+            roughness: (2f32 / (material.shininess + 2f32)).powf(0.25),
+            specular: 0.5,
+        })
+        .collect::<Vec<_>>();
+    info!("starting world build");
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut triangles = Vec::new();
+    let mut triangle_materials = Vec::new();
+    // Normals and texture coordinates are only kept if every model has them.
+    let with_normals = models.iter().all(|m| !m.mesh.normals.is_empty());
+    let with_uvs = models.iter().all(|m| !m.mesh.texcoords.is_empty());
+    for m in models.iter() {
+        let mesh = &m.mesh;
+        let material = mesh
+            .material_id
+            .filter(|&id| id < materials.len())
+            .ok_or_else(|| ObjError::NoMaterial(m.name.clone()))?;
+        let offset = positions.len() as u32;
+        positions.extend(
+            mesh.positions
+                .chunks_exact(3)
+                .map(|p| Point3::new(p[0], p[1], p[2])),
+        );
+        if with_normals {
+            normals.extend(
+                mesh.normals
+                    .chunks_exact(3)
+                    .map(|n| Vector3::new(n[0], n[1], n[2])),
+            );
+        }
+        if with_uvs {
+            uvs.extend(mesh.texcoords.chunks_exact(2).map(|uv| [uv[0], uv[1]]));
+        }
+        for face in mesh.indices.chunks_exact(3) {
+            triangles.push([face[0] + offset, face[1] + offset, face[2] + offset]);
+            triangle_materials.push(material as u32);
+        }
+    }
+    if triangles.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mesh = Mesh::new(
+        positions,
+        normals,
+        uvs,
+        triangles,
+        triangle_materials,
+        materials,
+    )
+    .map_err(ObjError::Mesh)?;
+    info!("built mesh of {} triangles", mesh.triangle_count());
+    Ok(vec![Object::Mesh(mesh)])
+}
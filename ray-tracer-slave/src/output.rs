@@ -0,0 +1,253 @@
+use image::codecs::hdr::HdrEncoder;
+use image::{ImageBuffer, ImageOutputFormat, ImageResult, Rgb};
+use serde::Deserialize;
+use std::io::Cursor;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Jpeg,
+    Png,
+    /// PNG with 16 bits per channel.
+    Png16,
+    /// OpenEXR with 32-bit float channels.
+    Exr,
+    /// Radiance RGBE.
+    Hdr,
+}
+
+impl OutputFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png | Self::Png16 => "image/png",
+            Self::Exr => "image/x-exr",
+            Self::Hdr => "image/vnd.radiance",
+        }
+    }
+
+    /// Guesses the format from a file name; `.png` is taken to mean 8 bits.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "exr" => Some(Self::Exr),
+            "hdr" => Some(Self::Hdr),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "jpeg" => Ok(Self::Jpeg),
+            "png" => Ok(Self::Png),
+            "png16" => Ok(Self::Png16),
+            "exr" => Ok(Self::Exr),
+            "hdr" => Ok(Self::Hdr),
+            other => Err(format!("unknown output format `{}`", other)),
+        }
+    }
+}
+
+/// Maps radiance to the 0..1 range of LDR formats.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Tonemap {
+    /// Cut off everything above 1.
+    #[default]
+    Clamp,
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+}
+
+/// Radiance past which every tonemap is as good as white. Brighter values
+/// are cut down to it, so the curves never overflow.
+const WHITE_RADIANCE: f32 = 1e6;
+
+impl Tonemap {
+    fn apply(self, x: f32) -> f32 {
+        let x = if x.is_nan() {
+            0f32
+        } else {
+            x.clamp(0f32, WHITE_RADIANCE)
+        };
+        match self {
+            Self::Clamp => x,
+            Self::Reinhard => x / (1f32 + x),
+            Self::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        }
+        .clamp(0f32, 1f32)
+    }
+}
+
+impl FromStr for Tonemap {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "clamp" => Ok(Self::Clamp),
+            "reinhard" => Ok(Self::Reinhard),
+            "aces" => Ok(Self::Aces),
+            other => Err(format!("unknown tonemap `{}`", other)),
+        }
+    }
+}
+
+pub struct LdrSettings {
+    pub tonemap: Tonemap,
+    /// In stops, applied before tonemapping.
+    pub exposure: f32,
+    pub gamma: f32,
+}
+
+impl LdrSettings {
+    fn encode(&self, radiance: f32) -> f32 {
+        self.tonemap
+            .apply(radiance * self.exposure.exp2())
+            .powf(1f32 / self.gamma)
+    }
+}
+
+/// Encodes an image of interleaved linear RGB radiance, rows from the top.
+/// EXR and HDR get the radiance as is; the other formats are tonemapped and
+/// gamma encoded according to `ldr` first.
+pub fn encode(
+    width: u32,
+    height: u32,
+    radiance: Vec<f32>,
+    format: OutputFormat,
+    ldr: &LdrSettings,
+) -> ImageResult<Vec<u8>> {
+    let mut c = Cursor::new(Vec::new());
+    match format {
+        OutputFormat::Jpeg | OutputFormat::Png => {
+            let pixels = radiance
+                .iter()
+                .map(|x| (ldr.encode(*x) * 255.999) as u8)
+                .collect();
+            let img: ImageBuffer<Rgb<u8>, _> =
+                ImageBuffer::from_vec(width, height, pixels).unwrap();
+            let format = match format {
+                OutputFormat::Jpeg => ImageOutputFormat::Jpeg(90),
+                _ => ImageOutputFormat::Png,
+            };
+            img.write_to(&mut c, format)?;
+        }
+        OutputFormat::Png16 => {
+            let pixels = radiance
+                .iter()
+                .map(|x| (ldr.encode(*x) * 65535.999) as u16)
+                .collect();
+            let img: ImageBuffer<Rgb<u16>, _> =
+                ImageBuffer::from_vec(width, height, pixels).unwrap();
+            img.write_to(&mut c, ImageOutputFormat::Png)?;
+        }
+        OutputFormat::Exr => {
+            let img: ImageBuffer<Rgb<f32>, _> =
+                ImageBuffer::from_vec(width, height, radiance).unwrap();
+            img.write_to(&mut c, ImageOutputFormat::OpenExr)?;
+        }
+        OutputFormat::Hdr => {
+            let pixels: Vec<Rgb<f32>> = radiance
+                .chunks_exact(3)
+                .map(|p| Rgb([p[0], p[1], p[2]]))
+                .collect();
+            HdrEncoder::new(&mut c).encode(&pixels, width as usize, height as usize)?;
+        }
+    }
+    Ok(c.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::codecs::hdr::HdrDecoder;
+    use image::{ColorType, GenericImageView};
+
+    const TONEMAPS: [Tonemap; 3] = [Tonemap::Clamp, Tonemap::Reinhard, Tonemap::Aces];
+
+    #[test]
+    fn test_tonemaps() {
+        for tonemap in TONEMAPS {
+            assert_eq!(tonemap.apply(0.0), 0.0);
+            assert_eq!(tonemap.apply(-1.0), 0.0);
+            assert_eq!(tonemap.apply(f32::NAN), 0.0);
+            let mut last = 0f32;
+            for x in [
+                0.1,
+                0.5,
+                1.0,
+                4.0,
+                100.0,
+                1e6,
+                1e20,
+                f32::MAX,
+                f32::INFINITY,
+            ] {
+                let y = tonemap.apply(x);
+                assert!((last..=1.0).contains(&y), "{} maps to {}", x, y);
+                last = y;
+            }
+        }
+    }
+
+    #[test]
+    fn test_exposure_and_gamma() {
+        let ldr = LdrSettings {
+            tonemap: Tonemap::Clamp,
+            exposure: 1.0,
+            gamma: 2.0,
+        };
+        assert_eq!(ldr.encode(0.125), 0.5);
+        assert_eq!(ldr.encode(0.0), 0.0);
+        assert_eq!(ldr.encode(10.0), 1.0);
+    }
+
+    #[test]
+    fn test_formats_decode() {
+        let (width, height) = (5, 3);
+        let radiance: Vec<f32> = (0..width * height * 3).map(|i| i as f32 / 8.0).collect();
+        let ldr = LdrSettings {
+            tonemap: Tonemap::Reinhard,
+            exposure: 0.0,
+            gamma: 2.2,
+        };
+        for (format, color_type) in [
+            (OutputFormat::Jpeg, ColorType::Rgb8),
+            (OutputFormat::Png, ColorType::Rgb8),
+            (OutputFormat::Png16, ColorType::Rgb16),
+            (OutputFormat::Exr, ColorType::Rgb32F),
+        ] {
+            let encoded = encode(width, height, radiance.clone(), format, &ldr).unwrap();
+            let decoded = image::load_from_memory(&encoded).unwrap();
+            assert_eq!(decoded.dimensions(), (width, height));
+            assert_eq!(decoded.color(), color_type);
+            if format == OutputFormat::Exr {
+                assert_eq!(decoded.to_rgb32f().as_raw(), &radiance);
+            }
+        }
+
+        // `image` only decodes HDR to 8 bits, so it is read back by hand.
+        let encoded = encode(width, height, radiance.clone(), OutputFormat::Hdr, &ldr).unwrap();
+        let decoder = HdrDecoder::new(encoded.as_slice()).unwrap();
+        let metadata = decoder.metadata();
+        assert_eq!((metadata.width, metadata.height), (width, height));
+        let pixels = decoder.read_image_hdr().unwrap();
+        for (pixel, expected) in pixels.iter().zip(radiance.chunks_exact(3)) {
+            // RGBE shares one exponent between the channels, which leaves 8
+            // bits of mantissa for the brightest.
+            let brightest = expected.iter().copied().fold(0f32, f32::max);
+            for (channel, expected) in pixel.0.iter().zip(expected) {
+                assert!((channel - expected).abs() <= brightest / 128.0);
+            }
+        }
+    }
+}
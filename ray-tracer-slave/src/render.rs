@@ -0,0 +1,503 @@
+use crate::{
+    color::{self, Color},
+    environment::EnvironmentMap,
+    lights::{Light, LightList},
+    scene::Background,
+    shapes::{IntersectionTable, Object, WorldList, T_MAX},
+    world_hash, WorkItem,
+};
+use bvh::bvh::BVH;
+use bvh::ray::Ray;
+use bvh::{Point3, Vector3};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+use serde::{Deserialize, Serialize};
+
+/// A world ready to render: its objects along with the BVH and light list
+/// built from them.
+pub struct World {
+    pub objects: WorldList,
+    pub bvh: BVH,
+    pub lights: LightList,
+}
+
+/// A world as the controller ships it: its objects, in the order the BVH
+/// built over them expects, along with that BVH, so that it is built once
+/// per world rather than on every slave.
+#[derive(Serialize, Deserialize)]
+pub struct WorldData {
+    pub objects: Vec<Object>,
+    pub bvh: BVH,
+}
+
+impl WorldData {
+    /// Builds the BVH over `objects`, reordering them to match it, along
+    /// with that of any mesh whose own BVH doesn't fit it.
+    pub fn build(mut objects: Vec<Object>) -> Self {
+        for object in &mut objects {
+            if let Object::Mesh(mesh) = object {
+                if !mesh.is_consistent() {
+                    mesh.rebuild();
+                }
+            }
+        }
+        let bvh = BVH::build_binned(&mut objects, BVH_LEAF_SIZE);
+        Self { objects, bvh }
+    }
+
+    /// The world's hash, which only covers the objects, meshes' BVHs
+    /// included. The world's BVH is checked against them instead.
+    pub fn hash(&self) -> String {
+        world_hash(&self.objects)
+    }
+}
+
+/// Most shapes a BVH leaf holds, where testing them all is cheaper than
+/// splitting them further.
+pub(crate) const BVH_LEAF_SIZE: usize = 4;
+
+impl World {
+    pub fn new(objects: Vec<Object>) -> Self {
+        Self::with_bvh(WorldData::build(objects))
+    }
+
+    /// Takes a world whose BVH was built elsewhere, or hands it back if the
+    /// BVH doesn't fit its objects or a mesh's BVH doesn't fit the mesh.
+    pub fn prebuilt(data: WorldData) -> Result<Self, WorldData> {
+        let meshes_fit = data.objects.iter().all(|object| match object {
+            Object::Mesh(mesh) => mesh.is_consistent(),
+            _ => true,
+        });
+        if !meshes_fit || !data.bvh.is_consistent(&data.objects) {
+            return Err(data);
+        }
+        Ok(Self::with_bvh(data))
+    }
+
+    fn with_bvh(WorldData { objects, bvh }: WorldData) -> Self {
+        let lights = LightList::new(&objects);
+        Self {
+            objects: WorldList::from_vec(objects),
+            bvh,
+            lights,
+        }
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionTable> {
+        let (t, (object, part)) =
+            self.bvh
+                .closest_hit(ray, self.objects.get(), T_MAX, |object, t_max| {
+                    let (t, part) = object.hit(ray, t_max)?;
+                    Some((t, (object, part)))
+                })?;
+        Some(IntersectionTable::new(object, part, ray.at(t), ray))
+    }
+
+    /// Whether anything is in the way of a light `distance` away from `from`
+    /// along `direction`.
+    fn occluded(&self, from: Point3, direction: Vector3, distance: f32) -> bool {
+        let ray = Ray::new(from, direction);
+        let t_max = (distance - SHADOW_EPSILON).min(T_MAX);
+        self.bvh.any_hit(&ray, self.objects.get(), t_max, |object| {
+            object.blocks(&ray, t_max)
+        })
+    }
+}
+
+/// How far short of a light a shadow ray may hit something without counting
+/// as blocked, which stops lights from shadowing themselves.
+const SHADOW_EPSILON: f32 = 1e-3;
+
+/// Weight of a sample taken with density `pdf` that could also have been
+/// taken with density `other`, by Veach's power heuristic.
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other * other);
+    if a + b > 0f32 {
+        a / (a + b)
+    } else {
+        0f32
+    }
+}
+
+/// What rays leaving the world see: a background, along with the image it
+/// shows if it is an environment map.
+pub struct Sky<'a> {
+    background: &'a Background,
+    map: Option<&'a EnvironmentMap>,
+}
+
+impl<'a> Sky<'a> {
+    pub fn new(background: &'a Background, map: Option<&'a EnvironmentMap>) -> Self {
+        Self { background, map }
+    }
+
+    /// The map along with its intensity and rotation in radians.
+    fn environment(&self) -> Option<(&EnvironmentMap, f32, f32)> {
+        match *self.background {
+            Background::Environment {
+                intensity,
+                rotation,
+                ..
+            } => Some((self.map?, intensity, rotation.to_radians())),
+            _ => None,
+        }
+    }
+
+    fn radiance(&self, direction: Vector3) -> Color {
+        match self.environment() {
+            Some((map, intensity, rotation)) => {
+                map.radiance(rotate_y(direction, -rotation)) * intensity
+            }
+            None => self.background.color(direction),
+        }
+    }
+
+    /// Picks a direction towards the bright parts of the map, returning it
+    /// with the radiance from there and its density per solid angle.
+    fn sample(&self, rng: &mut SmallRng) -> Option<(Vector3, Color, f32)> {
+        let (map, intensity, rotation) = self.environment()?;
+        let (direction, radiance, pdf) = map.sample(rng)?;
+        Some((rotate_y(direction, rotation), radiance * intensity, pdf))
+    }
+
+    /// Density of `sample` picking `direction`; nothing but maps is sampled.
+    fn pdf(&self, direction: Vector3) -> f32 {
+        self.environment().map_or(0f32, |(map, _, rotation)| {
+            map.pdf(rotate_y(direction, -rotation))
+        })
+    }
+}
+
+fn rotate_y(v: Vector3, angle: f32) -> Vector3 {
+    let (sin, cos) = angle.sin_cos();
+    Vector3::new(v.x * cos + v.z * sin, v.y, v.z * cos - v.x * sin)
+}
+
+/// Renders `samples` samples per pixel of `tile` and returns their mean as
+/// interleaved linear RGB, or nothing if `is_cancelled` turned true meanwhile.
+/// `environment` is the image `work.background` shows, if it is a map.
+pub fn render_pass(
+    work: &WorkItem,
+    world: &World,
+    environment: Option<&EnvironmentMap>,
+    is_cancelled: impl Fn() -> bool + Sync,
+) -> Option<Vec<f32>> {
+    let sky = Sky::new(&work.background, environment);
+    let image_height = work.render_meta.height;
+    let image_width = work.render_meta.width;
+    let tile = work.render_meta.tile(work.tile_no);
+    let mut img_buff = vec![0f32; tile.height as usize * tile.width as usize * 3];
+    img_buff
+        .par_chunks_exact_mut(tile.width as usize * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            if is_cancelled() {
+                return;
+            }
+            let y = tile.y as usize + y;
+            let mut rng = SmallRng::seed_from_u64(
+                work.render_meta
+                    .seed
+                    .wrapping_add((y * image_width as usize + tile.x as usize) as u64)
+                    .wrapping_add((work.pass as u64) << 40),
+            );
+            for (x, p) in row.chunks_exact_mut(3).enumerate() {
+                let x = tile.x as usize + x;
+                let y = image_height as usize - y - 1;
+                let mut pix_color = color::BLACK;
+                for _ in 0..work.samples {
+                    let r = work.camera.get_ray(x as u32, y as u32, &mut rng);
+                    pix_color += ray_color(
+                        r,
+                        world,
+                        &sky,
+                        &work.lights,
+                        work.render_meta.max_depth + 1,
+                        &mut rng,
+                    );
+                }
+                let pix_color = pix_color / work.samples as f32;
+                [p[0], p[1], p[2]] = [pix_color.r, pix_color.g, pix_color.b];
+            }
+        });
+    (!is_cancelled()).then_some(img_buff)
+}
+
+/// Bounces after which paths may be cut short by Russian roulette.
+const ROULETTE_DEPTH: u32 = 3;
+
+/// Radiance arriving along `ray`, following its path for up to `depth`
+/// surfaces.
+pub fn ray_color(
+    mut ray: Ray,
+    world: &World,
+    sky: &Sky,
+    lights: &[Light],
+    depth: u32,
+    rng: &mut SmallRng,
+) -> Color {
+    let mut radiance = color::BLACK;
+    // What the path so far lets through of light arriving along `ray`.
+    let mut throughput = color::WHITE;
+    // The density the last bounce picked `ray` with, if light sampling there
+    // could have found the same light; light found this way is then weighted
+    // against light sampling.
+    let mut bsdf_pdf: Option<f32> = None;
+    for bounce in 0..depth {
+        let Some(table) = world.intersect(&ray) else {
+            let mut sky_radiance = sky.radiance(ray.direction);
+            if let Some(bsdf_pdf) = bsdf_pdf {
+                sky_radiance = sky_radiance * power_heuristic(bsdf_pdf, sky.pdf(ray.direction));
+            }
+            radiance += throughput.blend(&sky_radiance);
+            break;
+        };
+        let wo = -ray.direction;
+        let mut emitted = table.material.emitted();
+        if let Some(bsdf_pdf) = bsdf_pdf {
+            // Measured like `sample_light` does, with the surface's own normal.
+            let distance = (table.point - ray.origin).length();
+            let cos_light = table.outward.dot(wo).abs();
+            let light_pdf = world.lights.pdf(emitted) * distance * distance / cos_light;
+            emitted = emitted * power_heuristic(bsdf_pdf, light_pdf);
+        }
+        let direct = direct_light(&table, wo, world, sky, lights, rng);
+        radiance += throughput.blend(&(emitted + direct));
+
+        let Some(sample) = table
+            .material
+            .sample(wo, table.normal, table.front_face, rng)
+        else {
+            break;
+        };
+        throughput = throughput.blend(&sample.weight);
+        if bounce >= ROULETTE_DEPTH {
+            // Dim paths stop early, and the ones that go on make up for them.
+            let survival = throughput.r.max(throughput.g).max(throughput.b).min(0.95);
+            if rng.gen::<f32>() >= survival {
+                break;
+            }
+            throughput = throughput / survival;
+        }
+        bsdf_pdf = (!sample.specular).then_some(sample.pdf);
+        ray = Ray::new(table.point, sample.direction);
+    }
+    radiance
+}
+
+/// Light reaching `table` straight from the lights and the sky.
+fn direct_light(
+    table: &IntersectionTable,
+    wo: Vector3,
+    world: &World,
+    sky: &Sky,
+    lights: &[Light],
+    rng: &mut SmallRng,
+) -> Color {
+    let mut direct = sample_light(table, wo, world, rng) + sample_sky(table, wo, world, sky, rng);
+    for light in lights {
+        let Some(incident) = light.illuminate(table.point, rng) else {
+            continue;
+        };
+        let f = table.material.eval(wo, incident.direction, table.normal);
+        if f != color::BLACK && !world.occluded(table.point, incident.direction, incident.distance)
+        {
+            direct += f.blend(&incident.irradiance);
+        }
+    }
+    direct
+}
+
+/// Light reaching `table` straight from a point picked on one of the lights,
+/// weighted against finding it by sampling the BSDF.
+fn sample_light(
+    table: &IntersectionTable,
+    wo: Vector3,
+    world: &World,
+    rng: &mut SmallRng,
+) -> Color {
+    let Some(light) = world.lights.sample(world.objects.get(), rng) else {
+        return color::BLACK;
+    };
+    let offset = light.point - table.point;
+    let distance = offset.length();
+    let wi = offset / distance;
+    // Emissive surfaces give off light on both sides.
+    let cos_light = light.normal.dot(wi).abs();
+    let f = table.material.eval(wo, wi, table.normal);
+    if cos_light <= 0f32 || f == color::BLACK || world.occluded(table.point, wi, distance) {
+        return color::BLACK;
+    }
+    let light_pdf = light.pdf * distance * distance / cos_light;
+    let bsdf_pdf = table.material.pdf(wo, wi, table.normal);
+    f.blend(&light.emitted) * (power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
+}
+
+/// Light reaching `table` from a direction picked on the sky, weighted against
+/// finding it by sampling the BSDF.
+fn sample_sky(
+    table: &IntersectionTable,
+    wo: Vector3,
+    world: &World,
+    sky: &Sky,
+    rng: &mut SmallRng,
+) -> Color {
+    let Some((wi, radiance, sky_pdf)) = sky.sample(rng) else {
+        return color::BLACK;
+    };
+    let f = table.material.eval(wo, wi, table.normal);
+    if sky_pdf <= 0f32 || f == color::BLACK || world.occluded(table.point, wi, f32::INFINITY) {
+        return color::BLACK;
+    }
+    let bsdf_pdf = table.material.pdf(wo, wi, table.normal);
+    f.blend(&radiance) * (power_heuristic(sky_pdf, bsdf_pdf) / sky_pdf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{material::Material, shapes::sphere::Sphere};
+
+    /// Directions of rays from `origin` through a `n` by `n` grid over the
+    /// square of side `size` centred on `target`, facing `+z` if `vertical`
+    /// and `+y` if not.
+    fn directions(
+        origin: Point3,
+        target: Point3,
+        size: f32,
+        n: u32,
+        vertical: bool,
+    ) -> Vec<Vector3> {
+        (0..n * n)
+            .map(|i| {
+                let u = ((i % n) as f32 + 0.5) / n as f32 - 0.5;
+                let v = ((i / n) as f32 + 0.5) / n as f32 - 0.5;
+                let offset = if vertical {
+                    Vector3::new(u, v, 0.0)
+                } else {
+                    Vector3::new(u, 0.0, v)
+                };
+                (target + offset * size - origin).normalize()
+            })
+            .collect()
+    }
+
+    fn mean(colors: impl Iterator<Item = Color>, count: usize) -> Color {
+        colors.fold(color::BLACK, |sum, color| sum + color) / count as f32
+    }
+
+    /// Radiance along `ray` found by following the BSDF alone, without
+    /// sampling lights or the sky.
+    fn bsdf_only(mut ray: Ray, world: &World, sky: &Sky, depth: u32, rng: &mut SmallRng) -> Color {
+        let mut radiance = color::BLACK;
+        let mut throughput = color::WHITE;
+        for _ in 0..depth {
+            let Some(table) = world.intersect(&ray) else {
+                radiance += throughput.blend(&sky.radiance(ray.direction));
+                break;
+            };
+            radiance += throughput.blend(&table.material.emitted());
+            let Some(sample) =
+                table
+                    .material
+                    .sample(-ray.direction, table.normal, table.front_face, rng)
+            else {
+                break;
+            };
+            throughput = throughput.blend(&sample.weight);
+            ray = Ray::new(table.point, sample.direction);
+        }
+        radiance
+    }
+
+    /// White surfaces under a constant sky lose no light, so however often
+    /// paths bounce between them they see the sky's color.
+    #[test]
+    fn test_white_furnace() {
+        let white = Material::Lambertian {
+            albedo: color::WHITE,
+        };
+        let world = World::new(vec![
+            Object::Sphere(Sphere::new(1.0, Point3::new(0.0, 0.0, 0.0), white)),
+            Object::Sphere(Sphere::new(1.0, Point3::new(2.1, 0.0, 0.0), white)),
+            Object::Sphere(Sphere::new(1.0, Point3::new(1.05, 1.8, 0.0), white)),
+        ]);
+        let sky_color = Color::from_slice([0.5, 0.7, 0.9]);
+        let background = Background::Constant(sky_color);
+        let sky = Sky::new(&background, None);
+        let mut rng = SmallRng::seed_from_u64(1);
+        let origin = Point3::new(1.05, 0.6, 6.0);
+        let directions = directions(origin, Point3::new(1.05, 0.6, 0.0), 3.0, 100, true);
+        let count = directions.len();
+        let mean = mean(
+            directions.into_iter().map(|direction| {
+                ray_color(Ray::new(origin, direction), &world, &sky, &[], 64, &mut rng)
+            }),
+            count,
+        );
+        for (got, expected) in [
+            (mean.r, sky_color.r),
+            (mean.g, sky_color.g),
+            (mean.b, sky_color.b),
+        ] {
+            assert!(
+                (got - expected).abs() < 0.02 * expected,
+                "{} vs {}",
+                got,
+                expected
+            );
+        }
+    }
+
+    /// Sampling an emissive sphere and weighting it against the BSDF finds
+    /// as much light on a floor below it as following the BSDF alone does.
+    #[test]
+    fn test_light_sampling_is_unbiased() {
+        let world = World::new(vec![
+            Object::Sphere(Sphere::new(
+                1000.0,
+                Point3::new(0.0, -1000.0, 0.0),
+                Material::Lambertian {
+                    albedo: Color::from_slice([0.5, 0.5, 0.5]),
+                },
+            )),
+            Object::Sphere(Sphere::new(
+                1.0,
+                Point3::new(0.0, 3.0, 0.0),
+                Material::Emissive {
+                    color: color::WHITE,
+                    strength: 4.0,
+                },
+            )),
+        ]);
+        let background = Background::Constant(color::BLACK);
+        let sky = Sky::new(&background, None);
+        let origin = Point3::new(0.0, 1.0, 6.0);
+        let directions = directions(origin, Point3::ZERO, 4.0, 200, false);
+        let count = directions.len();
+        let mut rng = SmallRng::seed_from_u64(2);
+        let sampled = mean(
+            directions.iter().map(|&direction| {
+                ray_color(Ray::new(origin, direction), &world, &sky, &[], 16, &mut rng)
+            }),
+            count,
+        );
+        // Light sampling at the last bounce finds light a path one bounce
+        // longer would have.
+        let followed = mean(
+            directions.iter().map(|&direction| {
+                bsdf_only(Ray::new(origin, direction), &world, &sky, 17, &mut rng)
+            }),
+            count,
+        );
+        assert!(sampled.r > 0.0);
+        assert!(
+            (sampled.r - followed.r).abs() < 0.05 * followed.r,
+            "{} vs {}",
+            sampled.r,
+            followed.r
+        );
+    }
+}
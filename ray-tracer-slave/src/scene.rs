@@ -0,0 +1,495 @@
+use crate::{
+    camera::CameraSettings,
+    color::{self, Color},
+    lights::Light,
+    material::Material,
+    shapes::{
+        mesh::{Mesh, MeshError, Triangle},
+        sphere::Sphere,
+        Object,
+    },
+    Point3,
+};
+use bvh::Vector3;
+use displaydoc::Display;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+/// Scene file version understood by this build.
+pub const SCENE_VERSION: u32 = 1;
+
+/// Largest width or height of an image, which the controller keeps whole in
+/// memory while it is rendered.
+pub const MAX_IMAGE_SIZE: u32 = 8192;
+
+/// Largest tile edge, which bounds the size of a slave's result.
+pub const MAX_TILE_SIZE: u32 = 1024;
+
+#[derive(Debug, Display)]
+pub enum SceneError {
+    /// failed to parse scene: {0}
+    Parse(serde_json::Error),
+    /// unsupported scene version {0}
+    UnsupportedVersion(u32),
+    /// object references unknown material `{0}`
+    UnknownMaterial(String),
+    /// {0}
+    Mesh(MeshError),
+    /// resolution must be non-zero, got {0}x{1}
+    InvalidResolution(u32, u32),
+    /// {0} must be positive
+    NonPositive(&'static str),
+    /// {0} must be at most {1}
+    TooLarge(&'static str, u32),
+}
+
+impl std::error::Error for SceneError {}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Scene {
+    pub version: u32,
+    #[serde(default)]
+    pub camera: CameraSettings,
+    #[serde(default)]
+    pub render: RenderSettings,
+    #[serde(default, deserialize_with = "background_or_legacy")]
+    pub background: Background,
+    #[serde(default)]
+    pub lights: Vec<Light>,
+    #[serde(default)]
+    pub materials: HashMap<String, SceneMaterial>,
+    #[serde(default)]
+    pub objects: Vec<SceneObject>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    /// Fixed seed for reproducible renders; a random one is picked per job
+    /// when absent.
+    pub seed: Option<u64>,
+    pub gamma: f32,
+    /// Send back intermediate passes so previews are available while rendering.
+    pub progressive: bool,
+    /// Edge length of the square tiles the image is split into.
+    pub tile_size: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            samples_per_pixel: 100,
+            max_depth: 10,
+            seed: None,
+            gamma: 2f32,
+            progressive: false,
+            tile_size: 64,
+        }
+    }
+}
+
+impl RenderSettings {
+    pub fn validate(&self) -> Result<(), SceneError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(SceneError::InvalidResolution(self.width, self.height));
+        }
+        for (name, size, max) in [
+            ("width", self.width, MAX_IMAGE_SIZE),
+            ("height", self.height, MAX_IMAGE_SIZE),
+            ("tile_size", self.tile_size, MAX_TILE_SIZE),
+        ] {
+            if size > max {
+                return Err(SceneError::TooLarge(name, max));
+            }
+        }
+        if self.samples_per_pixel == 0 {
+            return Err(SceneError::NonPositive("samples_per_pixel"));
+        }
+        if self.tile_size == 0 {
+            return Err(SceneError::NonPositive("tile_size"));
+        }
+        if self.gamma.is_nan() || self.gamma <= 0f32 {
+            return Err(SceneError::NonPositive("gamma"));
+        }
+        Ok(())
+    }
+}
+
+/// What rays that escape the scene see.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Background {
+    Constant(Color),
+    /// Blended vertically between `bottom` and `top`.
+    Gradient {
+        top: Color,
+        bottom: Color,
+    },
+    /// An equirectangular image, with up at the top and -Z in the middle,
+    /// turned `rotation` degrees about the vertical axis. `map` is the hash
+    /// it was uploaded under; scene files name the image file instead.
+    Environment {
+        map: String,
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+        #[serde(default)]
+        rotation: f32,
+    },
+}
+
+fn default_intensity() -> f32 {
+    1f32
+}
+
+const DEFAULT_TOP: Color = color::WHITE;
+const DEFAULT_BOTTOM: Color = Color {
+    r: 0.3,
+    g: 0.3,
+    b: 0.8f32,
+};
+
+impl Default for Background {
+    fn default() -> Self {
+        Self::Gradient {
+            top: DEFAULT_TOP,
+            bottom: DEFAULT_BOTTOM,
+        }
+    }
+}
+
+impl Background {
+    /// The colour seen along `direction`, for backgrounds that aren't an
+    /// image.
+    pub fn color(&self, direction: Vector3) -> Color {
+        match *self {
+            Self::Constant(color) => color,
+            Self::Gradient { top, bottom } => {
+                let t = direction.normalize_or_zero().y * 0.5 + 1f32;
+                t * top + (1f32 - t) * bottom
+            }
+            Self::Environment { .. } => color::BLACK,
+        }
+    }
+
+    /// Name of the environment map this background shows, if any.
+    pub fn environment_map(&self) -> Option<&str> {
+        match self {
+            Self::Environment { map, .. } => Some(map),
+            _ => None,
+        }
+    }
+}
+
+/// Accepts the original gradient-only format alongside `Background`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SceneBackground {
+    Background(Background),
+    Legacy(LegacyBackground),
+}
+
+/// Anything else isn't mistaken for it, so a misspelt background is an
+/// error instead of the default gradient.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LegacyBackground {
+    #[serde(default = "legacy_top")]
+    top: Color,
+    #[serde(default = "legacy_bottom")]
+    bottom: Color,
+}
+
+fn legacy_top() -> Color {
+    DEFAULT_TOP
+}
+
+fn legacy_bottom() -> Color {
+    DEFAULT_BOTTOM
+}
+
+fn background_or_legacy<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Background, D::Error> {
+    Ok(match SceneBackground::deserialize(deserializer)? {
+        SceneBackground::Background(background) => background,
+        SceneBackground::Legacy(LegacyBackground { top, bottom }) => {
+            Background::Gradient { top, bottom }
+        }
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum SceneMaterial {
+    Material(Material),
+    Legacy(LegacyMaterial),
+}
+
+/// The original format, where `roughness` blends between a diffuse and a
+/// mirror bounce and anything with `emission` is a light. Misspelt fields are
+/// an error rather than left at their defaults.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct LegacyMaterial {
+    pub albedo: Color,
+    #[serde(default)]
+    pub roughness: f32,
+    #[serde(default)]
+    pub emission: f32,
+}
+
+impl SceneMaterial {
+    pub fn material(&self) -> Material {
+        match *self {
+            Self::Material(material) => material,
+            Self::Legacy(LegacyMaterial {
+                albedo, emission, ..
+            }) if emission > 0f32 => Material::Emissive {
+                color: albedo,
+                strength: emission,
+            },
+            Self::Legacy(LegacyMaterial {
+                albedo, roughness, ..
+            }) if roughness > 0f32 => Material::Metal {
+                albedo,
+                roughness: 1f32 - roughness.min(1f32),
+            },
+            Self::Legacy(LegacyMaterial { albedo, .. }) => Material::Lambertian { albedo },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum SceneObject {
+    Sphere {
+        center: Point3,
+        radius: f32,
+        material: String,
+    },
+    Triangle {
+        a: Point3,
+        b: Point3,
+        c: Point3,
+        material: String,
+    },
+    Mesh {
+        positions: Vec<Point3>,
+        indices: Vec<[u32; 3]>,
+        material: String,
+    },
+}
+
+impl Scene {
+    pub fn from_json(data: &[u8]) -> Result<Self, SceneError> {
+        let scene: Self = serde_json::from_slice(data).map_err(SceneError::Parse)?;
+        if scene.version != SCENE_VERSION {
+            return Err(SceneError::UnsupportedVersion(scene.version));
+        }
+        scene.render.validate()?;
+        Ok(scene)
+    }
+
+    fn material(&self, name: &str) -> Result<Material, SceneError> {
+        self.materials
+            .get(name)
+            .map(SceneMaterial::material)
+            .ok_or_else(|| SceneError::UnknownMaterial(name.to_owned()))
+    }
+
+    pub fn build_world(&self) -> Result<Vec<Object>, SceneError> {
+        let mut world = Vec::new();
+        for object in &self.objects {
+            match object {
+                SceneObject::Sphere {
+                    center,
+                    radius,
+                    material,
+                } => {
+                    let m = self.material(material)?;
+                    world.push(Object::Sphere(Sphere::new(*radius, *center, m)));
+                }
+                SceneObject::Triangle { a, b, c, material } => {
+                    let m = self.material(material)?;
+                    world.push(Object::Triangle(Triangle::new(*a, *b, *c, m)));
+                }
+                SceneObject::Mesh {
+                    positions,
+                    indices,
+                    material,
+                } => {
+                    let m = self.material(material)?;
+                    let mesh = Mesh::new(
+                        positions.clone(),
+                        Vec::new(),
+                        Vec::new(),
+                        indices.clone(),
+                        vec![0; indices.len()],
+                        vec![m],
+                    )
+                    .map_err(SceneError::Mesh)?;
+                    if mesh.triangle_count() > 0 {
+                        world.push(Object::Mesh(mesh));
+                    }
+                }
+            }
+        }
+        Ok(world)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(json: &str) -> Result<Scene, SceneError> {
+        Scene::from_json(json.as_bytes())
+    }
+
+    fn background(json: &str) -> Result<Background, SceneError> {
+        parse(&format!(r#"{{"version": 1, "background": {}}}"#, json)).map(|scene| scene.background)
+    }
+
+    #[test]
+    fn test_backgrounds() {
+        let gray = Color::from_slice([0.5, 0.5, 0.5]);
+        assert_eq!(
+            background(r#"{"top": {"r": 0.5, "g": 0.5, "b": 0.5}}"#).unwrap(),
+            Background::Gradient {
+                top: gray,
+                bottom: DEFAULT_BOTTOM
+            }
+        );
+        assert_eq!(
+            background(r#"{"Constant": {"r": 0.5, "g": 0.5, "b": 0.5}}"#).unwrap(),
+            Background::Constant(gray)
+        );
+        assert_eq!(
+            background(r#"{"Environment": {"map": "sky.hdr"}}"#).unwrap(),
+            Background::Environment {
+                map: "sky.hdr".to_owned(),
+                intensity: 1.0,
+                rotation: 0.0
+            }
+        );
+        assert_eq!(
+            parse(r#"{"version": 1}"#).unwrap().background,
+            Background::default()
+        );
+        for misspelt in [
+            r#"{"Enviroment": {"map": "sky.hdr"}}"#,
+            r#"{"top": {"r": 1, "g": 1, "b": 1}, "botom": {"r": 0, "g": 0, "b": 0}}"#,
+        ] {
+            assert!(matches!(background(misspelt), Err(SceneError::Parse(_))));
+        }
+    }
+
+    #[test]
+    fn test_materials() {
+        let scene = parse(
+            r#"{
+                "version": 1,
+                "materials": {
+                    "new": {"Metal": {"albedo": {"r": 1, "g": 1, "b": 1}, "roughness": 0.2}},
+                    "matte": {"albedo": {"r": 1, "g": 0, "b": 0}},
+                    "shiny": {"albedo": {"r": 1, "g": 0, "b": 0}, "roughness": 0.25},
+                    "lamp": {"albedo": {"r": 1, "g": 1, "b": 1}, "emission": 3}
+                },
+                "objects": [{"Sphere": {"center": [0, 0, 0], "radius": 1, "material": "missing"}}]
+            }"#,
+        )
+        .unwrap();
+        let red = Color::from_slice([1.0, 0.0, 0.0]);
+        assert_eq!(
+            scene.material("new").unwrap(),
+            Material::Metal {
+                albedo: color::WHITE,
+                roughness: 0.2
+            }
+        );
+        assert_eq!(
+            scene.material("matte").unwrap(),
+            Material::Lambertian { albedo: red }
+        );
+        assert_eq!(
+            scene.material("shiny").unwrap(),
+            Material::Metal {
+                albedo: red,
+                roughness: 0.75
+            }
+        );
+        assert_eq!(
+            scene.material("lamp").unwrap(),
+            Material::Emissive {
+                color: color::WHITE,
+                strength: 3.0
+            }
+        );
+        assert!(matches!(
+            scene.build_world(),
+            Err(SceneError::UnknownMaterial(name)) if name == "missing"
+        ));
+        assert!(matches!(
+            parse(
+                r#"{
+                    "version": 1,
+                    "materials": {"shiny": {"albedo": {"r": 1, "g": 0, "b": 0}, "roughnes": 0.25}}
+                }"#
+            ),
+            Err(SceneError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(matches!(
+            parse(r#"{"version": 2}"#),
+            Err(SceneError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            parse(r#"{"version": 1, "render": {"width": 0}}"#),
+            Err(SceneError::InvalidResolution(0, 1080))
+        ));
+        for (field, render) in [
+            ("samples_per_pixel", r#"{"samples_per_pixel": 0}"#),
+            ("tile_size", r#"{"tile_size": 0}"#),
+            ("gamma", r#"{"gamma": -1}"#),
+        ] {
+            let scene = format!(r#"{{"version": 1, "render": {}}}"#, render);
+            assert!(matches!(
+                parse(&scene),
+                Err(SceneError::NonPositive(name)) if name == field
+            ));
+        }
+        for (field, render) in [
+            ("width", r#"{"width": 4000000000}"#),
+            ("height", r#"{"height": 8193}"#),
+            ("tile_size", r#"{"tile_size": 65536}"#),
+        ] {
+            let scene = format!(r#"{{"version": 1, "render": {}}}"#, render);
+            assert!(matches!(
+                parse(&scene),
+                Err(SceneError::TooLarge(name, _)) if name == field
+            ));
+        }
+        assert!(parse(r#"{"version": 1, "render": {"width": 8192, "height": 8192}}"#).is_ok());
+        assert!(matches!(
+            parse(
+                r#"{
+                    "version": 1,
+                    "materials": {"m": {"Lambertian": {"albedo": {"r": 1, "g": 1, "b": 1}}}},
+                    "objects": [{"Mesh": {"positions": [[0, 0, 0]], "indices": [[0, 0, 1]], "material": "m"}}]
+                }"#
+            )
+            .unwrap()
+            .build_world(),
+            Err(SceneError::Mesh(MeshError::VertexOutOfRange(1, 1)))
+        ));
+        assert!(parse(include_str!("../../scenes/spheres.json")).is_ok());
+        assert!(parse(include_str!("../../scenes/lights.json")).is_ok());
+    }
+}
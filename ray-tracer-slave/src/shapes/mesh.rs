@@ -1,15 +1,18 @@
-use crate::color::Color;
+use crate::{material::Material, render::BVH_LEAF_SIZE};
 use bvh::{
     aabb::{Bounded, AABB},
     bounding_hierarchy::BHShape,
+    bvh::BVH,
     ray::Ray,
     Point3, Vector3,
 };
+use displaydoc::Display;
+use rand::{rngs::SmallRng, Rng};
 use roots::Roots;
 use serde::{Deserialize, Serialize};
 use std::cmp::{max_by, min_by};
 
-use super::Intersectable;
+use super::{Intersectable, T_MIN};
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Triangle {
@@ -17,28 +20,17 @@ pub struct Triangle {
     b: Point3,
     c: Point3,
     node_index: usize,
-    p_albedo_at: Color,
-    p_roughness_at: f32,
-    p_emission_at: f32,
+    material: Material,
 }
 
 impl Triangle {
-    pub fn new(
-        a: Point3,
-        b: Point3,
-        c: Point3,
-        p_roughness_at: f32,
-        p_albedo_at: Color,
-        p_emission_at: f32,
-    ) -> Self {
+    pub fn new(a: Point3, b: Point3, c: Point3, material: Material) -> Self {
         Self {
             a,
             b,
             c,
             node_index: 0,
-            p_albedo_at,
-            p_roughness_at,
-            p_emission_at,
+            material,
         }
     }
 }
@@ -107,71 +99,627 @@ impl BHShape for Triangle {
 
 impl Intersectable for Triangle {
     fn get_roots(&self, ray: &Ray) -> Roots<f32> {
-        const EPSILON: f32 = 0.00001;
-        let a_to_b = self.b - self.a;
-        let a_to_c = self.c - self.a;
-
-        // Begin calculating determinant - also used to calculate u parameter
-        // u_vec lies in view plane
-        // length of a_to_c in view_plane = |u_vec| = |a_to_c|*sin(a_to_c, dir)
-        let u_vec = ray.direction.cross(a_to_c);
-
-        // If determinant is near zero, ray lies in plane of triangle
-        // The determinant corresponds to the parallelepiped volume:
-        // det = 0 => [dir, a_to_b, a_to_c] not linearly independant
-        let det = a_to_b.dot(u_vec);
-
-        // Only testing positive bound, thus enabling backface culling
-        // If backface culling is not desired write:
-        // det < EPSILON && det > -EPSILON
-        if det < EPSILON && det > -EPSILON {
-            return Roots::No([]);
+        triangle_roots(self.a, self.b, self.c, ray)
+    }
+
+    fn normal_at(&self, _: Point3) -> Vector3 {
+        triangle_normal(self.a, self.b, self.c)
+    }
+
+    fn material_at(&self, _: Point3) -> Material {
+        self.material
+    }
+
+    fn area(&self) -> f32 {
+        triangle_area(self.a, self.b, self.c)
+    }
+
+    fn sample_point(&self, rng: &mut SmallRng) -> (Point3, Vector3) {
+        let point = triangle_sample(self.a, self.b, self.c, rng);
+        (point, self.normal_at(point))
+    }
+}
+
+#[derive(Debug, Display)]
+pub enum MeshError {
+    /// mesh index {0} is out of range for {1} vertices
+    VertexOutOfRange(u32, usize),
+    /// material index {0} is out of range for {1} materials
+    MaterialOutOfRange(u32, usize),
+    /// mesh has {0} {1} for {2} vertices
+    AttributeCount(usize, &'static str, usize),
+    /// mesh has {0} materials for {1} triangles
+    MaterialCount(usize, usize),
+    /// mesh triangle order doesn't cover its {0} triangles once each
+    Order(usize),
+}
+
+impl std::error::Error for MeshError {}
+
+/// Triangles sharing one buffer of vertices, each referring to a material in
+/// the mesh's table. The world's BVH holds the mesh as a whole; it has a BVH
+/// of its own over its triangles, built along with it and shipped with it
+/// like the world's, to be checked with `is_consistent` before use.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(try_from = "MeshParts")]
+pub struct Mesh {
+    data: MeshData,
+    /// Triangles in the order `bvh` expects.
+    order: Vec<u32>,
+    bvh: BVH,
+    #[serde(skip_serializing)]
+    aabb: AABB,
+    node_index: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+struct MeshData {
+    positions: Vec<Point3>,
+    /// One per vertex, or none to shade the triangles flat.
+    normals: Vec<Vector3>,
+    /// One per vertex, or none.
+    uvs: Vec<[f32; 2]>,
+    triangles: Vec<[u32; 3]>,
+    /// Index into `materials` of each triangle's material.
+    triangle_materials: Vec<u32>,
+    materials: Vec<Material>,
+}
+
+/// A mesh as it is sent, before its indices are checked.
+#[derive(Deserialize)]
+struct MeshParts {
+    data: MeshData,
+    order: Vec<u32>,
+    bvh: BVH,
+    node_index: usize,
+}
+
+/// What the BVH of a mesh is built over, as triangles can't be bounded
+/// without the vertices.
+struct TriangleBounds {
+    triangle: u32,
+    aabb: AABB,
+    node_index: usize,
+}
+
+impl Bounded for TriangleBounds {
+    fn aabb(&self) -> AABB {
+        self.aabb
+    }
+}
+
+impl BHShape for TriangleBounds {
+    fn set_bh_node_index(&mut self, index: usize) {
+        self.node_index = index;
+    }
+
+    fn bh_node_index(&self) -> usize {
+        self.node_index
+    }
+}
+
+impl MeshData {
+    fn validate(&self) -> Result<(), MeshError> {
+        let vertex_count = self.positions.len();
+        for (count, name) in [(self.normals.len(), "normals"), (self.uvs.len(), "uvs")] {
+            if count != 0 && count != vertex_count {
+                return Err(MeshError::AttributeCount(count, name, vertex_count));
+            }
+        }
+        if self.triangle_materials.len() != self.triangles.len() {
+            return Err(MeshError::MaterialCount(
+                self.triangle_materials.len(),
+                self.triangles.len(),
+            ));
+        }
+        if let Some(&i) = self
+            .triangles
+            .iter()
+            .flatten()
+            .find(|&&i| i as usize >= vertex_count)
+        {
+            return Err(MeshError::VertexOutOfRange(i, vertex_count));
         }
+        if let Some(&i) = self
+            .triangle_materials
+            .iter()
+            .find(|&&i| i as usize >= self.materials.len())
+        {
+            return Err(MeshError::MaterialOutOfRange(i, self.materials.len()));
+        }
+        Ok(())
+    }
+
+    fn bounds(&self, triangle: u32) -> TriangleBounds {
+        TriangleBounds {
+            triangle,
+            aabb: self.triangles[triangle as usize]
+                .iter()
+                .fold(AABB::empty(), |aabb, &i| {
+                    aabb.grow(&self.positions[i as usize])
+                }),
+            node_index: 0,
+        }
+    }
+
+    fn aabb(&self) -> AABB {
+        (0..self.triangles.len() as u32).fold(AABB::empty(), |aabb, triangle| {
+            aabb.join(&self.bounds(triangle).aabb)
+        })
+    }
+}
+
+impl TryFrom<MeshParts> for Mesh {
+    type Error = MeshError;
+
+    /// Checks every index the BVH doesn't hold, which is left to
+    /// `is_consistent`.
+    fn try_from(parts: MeshParts) -> Result<Self, MeshError> {
+        parts.data.validate()?;
+        let mut seen = vec![false; parts.data.triangles.len()];
+        if parts.order.len() != seen.len()
+            || !parts.order.iter().all(|&triangle| {
+                seen.get_mut(triangle as usize)
+                    .is_some_and(|seen| !std::mem::replace(seen, true))
+            })
+        {
+            return Err(MeshError::Order(seen.len()));
+        }
+        Ok(Self {
+            aabb: parts.data.aabb(),
+            data: parts.data,
+            order: parts.order,
+            bvh: parts.bvh,
+            node_index: parts.node_index,
+        })
+    }
+}
+
+impl Mesh {
+    /// `normals` and `uvs` are either empty or have one entry per position;
+    /// `triangle_materials` has one entry per triangle.
+    pub fn new(
+        positions: Vec<Point3>,
+        normals: Vec<Vector3>,
+        uvs: Vec<[f32; 2]>,
+        triangles: Vec<[u32; 3]>,
+        triangle_materials: Vec<u32>,
+        materials: Vec<Material>,
+    ) -> Result<Self, MeshError> {
+        let data = MeshData {
+            positions,
+            normals,
+            uvs,
+            triangles,
+            triangle_materials,
+            materials,
+        };
+        data.validate()?;
+        Ok(Self::build(data))
+    }
+
+    fn build(data: MeshData) -> Self {
+        let mut bounds: Vec<_> = (0..data.triangles.len() as u32)
+            .map(|triangle| data.bounds(triangle))
+            .collect();
+        let bvh = BVH::build_binned(&mut bounds, BVH_LEAF_SIZE);
+        Self {
+            order: bounds.iter().map(|bounds| bounds.triangle).collect(),
+            aabb: data.aabb(),
+            data,
+            bvh,
+            node_index: 0,
+        }
+    }
 
-        let inv_det = 1.0 / det;
+    /// Whether the mesh's BVH fits its triangles.
+    pub fn is_consistent(&self) -> bool {
+        let bounds: Vec<_> = self
+            .order
+            .iter()
+            .map(|&triangle| self.data.bounds(triangle))
+            .collect();
+        self.bvh.is_consistent(&bounds)
+    }
 
-        // Vector from point a to ray origin
-        let a_to_origin = ray.origin - self.a;
+    /// Builds the mesh's BVH again, for when it isn't consistent.
+    pub fn rebuild(&mut self) {
+        let node_index = self.node_index;
+        *self = Self::build(std::mem::take(&mut self.data));
+        self.node_index = node_index;
+    }
 
-        // Calculate u parameter
-        let u = a_to_origin.dot(u_vec) * inv_det;
+    pub fn triangle_count(&self) -> usize {
+        self.data.triangles.len()
+    }
+
+    fn vertices(&self, triangle: usize) -> [Point3; 3] {
+        self.data.triangles[triangle].map(|i| self.data.positions[i as usize])
+    }
+
+    /// Distance along `ray` to the nearest triangle hit closer than `t_max`,
+    /// along with that triangle.
+    pub fn hit(&self, ray: &Ray, t_max: f32) -> Option<(f32, usize)> {
+        self.bvh.closest_hit_by_index(ray, t_max, |i, t_max| {
+            let triangle = self.order[i] as usize;
+            Some((self.hit_distance(triangle, ray, t_max)?, triangle))
+        })
+    }
+
+    /// Whether `ray` hits any triangle closer than `t_max`.
+    pub fn blocks(&self, ray: &Ray, t_max: f32) -> bool {
+        self.bvh.any_hit_by_index(ray, t_max, |i| {
+            self.hit_distance(self.order[i] as usize, ray, t_max)
+                .is_some()
+        })
+    }
 
-        // Test bounds: u < 0 || u > 1 => outside of triangle
-        if !(0.0..=1.0).contains(&u) {
-            return Roots::No([]);
+    fn hit_distance(&self, triangle: usize, ray: &Ray, t_max: f32) -> Option<f32> {
+        let [a, b, c] = self.vertices(triangle);
+        match triangle_roots(a, b, c, ray) {
+            Roots::One([t]) => Some(t).filter(|t| (T_MIN..t_max).contains(t)),
+            _ => None,
         }
+    }
+
+    /// Outward normal of `triangle`, along with the normal to shade `point`
+    /// on it with, which is interpolated from the vertex normals if there
+    /// are any.
+    pub fn normals_at(&self, triangle: usize, point: Point3) -> (Vector3, Vector3) {
+        let [a, b, c] = self.vertices(triangle);
+        let outward = triangle_normal(a, b, c);
+        if self.data.normals.is_empty() {
+            return (outward, outward);
+        }
+        let weights = barycentric(a, b, c, point);
+        let shading = self.data.triangles[triangle]
+            .iter()
+            .zip(weights)
+            .map(|(&i, weight)| weight * self.data.normals[i as usize])
+            .sum::<Vector3>()
+            .try_normalize()
+            .unwrap_or(outward);
+        (outward, shading)
+    }
+
+    pub fn material(&self, triangle: usize) -> Material {
+        self.data.materials[self.data.triangle_materials[triangle] as usize]
+    }
+
+    pub fn area(&self, triangle: usize) -> f32 {
+        let [a, b, c] = self.vertices(triangle);
+        triangle_area(a, b, c)
+    }
+
+    /// A point picked uniformly over `triangle`, with its outward normal.
+    pub fn sample_point(&self, triangle: usize, rng: &mut SmallRng) -> (Point3, Vector3) {
+        let [a, b, c] = self.vertices(triangle);
+        (triangle_sample(a, b, c, rng), triangle_normal(a, b, c))
+    }
+}
+
+impl Bounded for Mesh {
+    fn aabb(&self) -> AABB {
+        self.aabb
+    }
+}
+
+impl BHShape for Mesh {
+    fn set_bh_node_index(&mut self, index: usize) {
+        self.node_index = index;
+    }
+
+    fn bh_node_index(&self) -> usize {
+        self.node_index
+    }
+}
+
+/// Möller–Trumbore intersection of `ray` with the triangle `a`, `b`, `c`.
+fn triangle_roots(a: Point3, b: Point3, c: Point3, ray: &Ray) -> Roots<f32> {
+    const EPSILON: f32 = 0.00001;
+    let a_to_b = b - a;
+    let a_to_c = c - a;
 
-        // Prepare to test v parameter
-        let v_vec = a_to_origin.cross(a_to_b);
+    // Begin calculating determinant - also used to calculate u parameter
+    // u_vec lies in view plane
+    // length of a_to_c in view_plane = |u_vec| = |a_to_c|*sin(a_to_c, dir)
+    let u_vec = ray.direction.cross(a_to_c);
 
-        // Calculate v parameter and test bound
-        let v = ray.direction.dot(v_vec) * inv_det;
-        // The intersection lies outside of the triangle
-        if v < 0.0 || u + v > 1.0 {
-            return Roots::No([]);
+    // If determinant is near zero, ray lies in plane of triangle
+    // The determinant corresponds to the parallelepiped volume:
+    // det = 0 => [dir, a_to_b, a_to_c] not linearly independant
+    let det = a_to_b.dot(u_vec);
+
+    // Only testing positive bound, thus enabling backface culling
+    // If backface culling is not desired write:
+    // det < EPSILON && det > -EPSILON
+    if det < EPSILON && det > -EPSILON {
+        return Roots::No([]);
+    }
+
+    let inv_det = 1.0 / det;
+
+    // Vector from point a to ray origin
+    let a_to_origin = ray.origin - a;
+
+    // Calculate u parameter
+    let u = a_to_origin.dot(u_vec) * inv_det;
+
+    // Test bounds: u < 0 || u > 1 => outside of triangle
+    if !(0.0..=1.0).contains(&u) {
+        return Roots::No([]);
+    }
+
+    // Prepare to test v parameter
+    let v_vec = a_to_origin.cross(a_to_b);
+
+    // Calculate v parameter and test bound
+    let v = ray.direction.dot(v_vec) * inv_det;
+    // The intersection lies outside of the triangle
+    if v < 0.0 || u + v > 1.0 {
+        return Roots::No([]);
+    }
+
+    let dist = a_to_c.dot(v_vec) * inv_det;
+
+    if dist > EPSILON {
+        Roots::One([dist])
+    } else {
+        Roots::No([])
+    }
+}
+
+/// Outward normal of a triangle, on the side its vertices wind
+/// counterclockwise on.
+fn triangle_normal(a: Point3, b: Point3, c: Point3) -> Vector3 {
+    (a - b).cross(a - c).normalize_or_zero()
+}
+
+fn triangle_area(a: Point3, b: Point3, c: Point3) -> f32 {
+    (b - a).cross(c - a).length() / 2f32
+}
+
+/// A point picked uniformly over a triangle.
+fn triangle_sample(a: Point3, b: Point3, c: Point3, rng: &mut SmallRng) -> Point3 {
+    let u = rng.gen::<f32>().sqrt();
+    let v = rng.gen::<f32>();
+    (1f32 - u) * a + u * (1f32 - v) * b + u * v * c
+}
+
+/// Weights of `a`, `b` and `c` that make up `point`, which lies on the
+/// triangle.
+fn barycentric(a: Point3, b: Point3, c: Point3, point: Point3) -> [f32; 3] {
+    let normal = (b - a).cross(c - a);
+    let area = normal.length_squared();
+    if area == 0f32 {
+        return [1f32, 0f32, 0f32];
+    }
+    let u = (c - b).cross(point - b).dot(normal) / area;
+    let v = (a - c).cross(point - c).dot(normal) / area;
+    [u, v, 1f32 - u - v]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color;
+    use crate::shapes::{IntersectionTable, Object};
+    use crate::wire::Encoding;
+
+    /// A flat `n` by `n` grid of squares in the `xy` plane, facing `+z`,
+    /// with every other row emissive.
+    fn grid(n: u32) -> (Vec<Point3>, Vec<[u32; 3]>, Vec<u32>, Vec<Material>) {
+        let positions = (0..=n)
+            .flat_map(|y| (0..=n).map(move |x| Point3::new(x as f32, y as f32, 0.0)))
+            .collect();
+        let vertex = |x: u32, y: u32| y * (n + 1) + x;
+        let mut triangles = Vec::new();
+        let mut triangle_materials = Vec::new();
+        for y in 0..n {
+            for x in 0..n {
+                triangles.push([vertex(x, y), vertex(x + 1, y), vertex(x + 1, y + 1)]);
+                triangles.push([vertex(x, y), vertex(x + 1, y + 1), vertex(x, y + 1)]);
+                triangle_materials.extend([y % 2, y % 2]);
+            }
         }
+        let materials = vec![
+            Material::default(),
+            Material::Emissive {
+                color: color::WHITE,
+                strength: 2.0,
+            },
+        ];
+        (positions, triangles, triangle_materials, materials)
+    }
 
-        let dist = a_to_c.dot(v_vec) * inv_det;
+    #[test]
+    fn test_mesh_matches_triangles() {
+        let (positions, triangles, triangle_materials, materials) = grid(8);
+        let separate: Vec<_> = triangles
+            .iter()
+            .zip(&triangle_materials)
+            .map(|(vertices, &material)| {
+                let [a, b, c] = vertices.map(|i| positions[i as usize]);
+                Triangle::new(a, b, c, materials[material as usize])
+            })
+            .collect();
+        let mesh = Mesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            triangles,
+            triangle_materials,
+            materials,
+        )
+        .unwrap();
+        assert_eq!(mesh.aabb().min, Point3::ZERO);
+        assert_eq!(mesh.aabb().max, Point3::new(8.0, 8.0, 0.0));
 
-        if dist > EPSILON {
-            Roots::One([dist])
-        } else {
-            Roots::No([])
+        for (x, y) in [(0.25, 0.5), (3.7, 1.2), (7.9, 7.9), (5.5, 4.5)] {
+            let ray = Ray::new(Point3::new(x, y, 5.0), -Vector3::Z);
+            let (t, triangle) = mesh.hit(&ray, 100.0).unwrap();
+            let point = ray.at(t);
+            let expected = separate
+                .iter()
+                .find(|triangle| triangle.hit_distance(&ray, 100.0).is_some())
+                .unwrap();
+            assert_eq!(t, expected.hit_distance(&ray, 100.0).unwrap());
+            assert_eq!(mesh.material(triangle), expected.material_at(point));
+            assert_eq!(mesh.normals_at(triangle, point).0, Vector3::Z);
+            assert!(mesh.blocks(&ray, 100.0));
+            assert!(!mesh.blocks(&ray, 4.0));
         }
+        let miss = Ray::new(Point3::new(9.0, 4.0, 5.0), -Vector3::Z);
+        assert!(mesh.hit(&miss, 100.0).is_none());
     }
 
-    fn normal_at(&self, _: Point3) -> Vector3 {
-        (self.a - self.b).cross(self.a - self.c).normalize_or_zero()
+    #[test]
+    fn test_smooth_normals() {
+        let mesh = Mesh::new(
+            vec![Point3::ZERO, Point3::X, Point3::Y],
+            vec![Vector3::X, Vector3::Z, Vector3::Z],
+            vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]],
+            vec![[0, 1, 2]],
+            vec![0],
+            vec![Material::default()],
+        )
+        .unwrap();
+        let (outward, shading) = mesh.normals_at(0, Point3::ZERO);
+        assert_eq!(outward, Vector3::Z);
+        assert!((shading - Vector3::X).length() < 1e-6);
+        let (_, shading) = mesh.normals_at(0, Point3::new(0.5, 0.5, 0.0));
+        assert!((shading - Vector3::Z).length() < 1e-6);
+
+        // From below, the shading normal flips to face the ray while the
+        // outward one stays put.
+        let point = Point3::new(0.1, 0.1, 0.0);
+        let ray = Ray::new(Point3::new(0.1, 0.1, -1.0), Vector3::Z);
+        let table = IntersectionTable::new(&Object::Mesh(mesh.clone()), Some(0), point, &ray);
+        assert!(!table.front_face);
+        assert_eq!(table.outward, Vector3::Z);
+        assert_eq!(table.normal, -mesh.normals_at(0, point).1);
     }
 
-    fn roughness_at(&self, _: Point3) -> f32 {
-        self.p_roughness_at
+    #[test]
+    fn test_invalid_meshes() {
+        let new = |normals: Vec<Vector3>, triangles: Vec<[u32; 3]>, materials: Vec<u32>| {
+            Mesh::new(
+                vec![Point3::ZERO, Point3::X, Point3::Y],
+                normals,
+                Vec::new(),
+                triangles,
+                materials,
+                vec![Material::default()],
+            )
+        };
+        assert!(new(Vec::new(), vec![[0, 1, 2]], vec![0]).is_ok());
+        assert!(matches!(
+            new(Vec::new(), vec![[0, 1, 3]], vec![0]),
+            Err(MeshError::VertexOutOfRange(3, 3))
+        ));
+        assert!(matches!(
+            new(Vec::new(), vec![[0, 1, 2]], vec![1]),
+            Err(MeshError::MaterialOutOfRange(1, 1))
+        ));
+        assert!(matches!(
+            new(Vec::new(), vec![[0, 1, 2]], Vec::new()),
+            Err(MeshError::MaterialCount(0, 1))
+        ));
+        assert!(matches!(
+            new(vec![Vector3::Z], vec![[0, 1, 2]], vec![0]),
+            Err(MeshError::AttributeCount(1, "normals", 3))
+        ));
     }
 
-    fn albedo_at(&self, _: Point3) -> Color {
-        self.p_albedo_at
+    /// Sharing vertices and materials keeps a mesh, BVH and all, smaller on
+    /// the wire than the same triangles sent one by one with the world BVH
+    /// over them.
+    #[test]
+    fn test_mesh_payload_size() {
+        let (positions, triangles, triangle_materials, materials) = grid(64);
+        let separate: Vec<_> = triangles
+            .iter()
+            .zip(&triangle_materials)
+            .map(|(vertices, &material)| {
+                let [a, b, c] = vertices.map(|i| positions[i as usize]);
+                Object::Triangle(Triangle::new(a, b, c, materials[material as usize]))
+            })
+            .collect();
+        let mesh = Mesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            triangles,
+            triangle_materials,
+            materials,
+        )
+        .unwrap();
+        let mesh = vec![Object::Mesh(mesh)];
+        for encoding in [Encoding::Bincode, Encoding::BincodeZstd] {
+            let size = |world: &[Object]| {
+                encoding
+                    .encode(&crate::render::WorldData::build(world.to_vec()))
+                    .unwrap()
+                    .len()
+            };
+            let (separate, mesh) = (size(&separate), size(&mesh));
+            assert!(mesh < separate, "{} vs {} bytes", mesh, separate);
+        }
     }
-    fn emission_at(&self, _: Point3) -> f32 {
-        self.p_emission_at
+
+    /// A mesh decodes to the BVH it was sent with and encodes to the same
+    /// bytes again, which the world's hash relies on. Indices the BVH doesn't
+    /// hold are checked when it is decoded, and the BVH when it is used.
+    #[test]
+    fn test_mesh_round_trip() {
+        let (positions, triangles, triangle_materials, materials) = grid(8);
+        let mesh = Mesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            triangles,
+            triangle_materials,
+            materials,
+        )
+        .unwrap();
+        assert!(mesh.is_consistent());
+        let bytes = Encoding::Bincode.encode(&mesh).unwrap();
+        let decoded: Mesh = Encoding::Bincode.decode(&bytes).unwrap();
+        assert_eq!(Encoding::Bincode.encode(&decoded).unwrap(), bytes);
+        assert!(decoded.is_consistent());
+        assert_eq!(decoded.order, mesh.order);
+        assert_eq!(decoded.aabb().min, mesh.aabb().min);
+        assert_eq!(decoded.aabb().max, mesh.aabb().max);
+
+        let mut broken = mesh.clone();
+        broken.data.triangles[3][1] = 81;
+        let bytes = Encoding::Bincode.encode(&broken).unwrap();
+        assert!(Encoding::Bincode.decode::<Mesh>(&bytes).is_err());
+
+        let mut broken = mesh.clone();
+        broken.order[1] = broken.order[0];
+        let bytes = Encoding::Bincode.encode(&broken).unwrap();
+        assert!(matches!(
+            Encoding::Bincode.decode::<Mesh>(&bytes),
+            Err(crate::wire::WireError::Bincode(_))
+        ));
+
+        // Triangles swapped across the grid no longer fit the leaves holding
+        // them, which the world turns down and builds again.
+        let mut broken = mesh.clone();
+        let last = broken.order.len() - 1;
+        broken.order.swap(0, last);
+        let bytes = Encoding::Bincode.encode(&broken).unwrap();
+        let decoded: Mesh = Encoding::Bincode.decode(&bytes).unwrap();
+        assert!(!decoded.is_consistent());
+        let data = crate::render::WorldData {
+            objects: vec![Object::Mesh(decoded)],
+            bvh: crate::render::WorldData::build(vec![Object::Mesh(mesh)]).bvh,
+        };
+        let Err(data) = crate::render::World::prebuilt(data) else {
+            panic!("a damaged mesh BVH was taken");
+        };
+        let data = crate::render::WorldData::build(data.objects);
+        assert!(crate::render::World::prebuilt(data).is_ok());
     }
 }
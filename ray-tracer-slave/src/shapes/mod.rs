@@ -1,71 +1,122 @@
-use crate::color::Color;
+use crate::material::Material;
 use auto_impl::auto_impl;
 use bvh::{aabb::Bounded, bounding_hierarchy::BHShape, ray::Ray, Point3, Vector3};
+use rand::rngs::SmallRng;
 use roots::Roots;
 use serde::{Deserialize, Serialize};
-use std::cmp::Ordering;
 pub mod mesh;
 pub mod sphere;
-use mesh::Triangle;
+use mesh::{Mesh, Triangle};
 use sphere::Sphere;
 
 const T_MIN: f32 = 0.001;
-const T_MAX: f32 = 1000.0;
+/// Hits further away than this are ignored.
+pub const T_MAX: f32 = 1000.0;
 
 pub struct IntersectionTable {
     pub point: Point3,
+    /// Faces the ray, so it points inside when the ray comes from within.
+    /// Meshes with vertex normals shade with the interpolated one.
     pub normal: Vector3,
-    pub albedo: Color,
-    pub roughness: f32,
-    pub emission: f32,
+    /// The surface's own normal, pointing outside whichever side the ray
+    /// came from.
+    pub outward: Vector3,
+    /// Whether the ray hit the outside of the surface, which for triangles is
+    /// the side their vertices wind counterclockwise on.
+    pub front_face: bool,
+    pub material: Material,
 }
 
+impl IntersectionTable {
+    /// Describes where `ray` hits `part` of `object` at `point`.
+    pub fn new(object: &Object, part: Option<usize>, point: Point3, ray: &Ray) -> Self {
+        let (outward, shading) = object.normals_at(part, point);
+        let front_face = ray.direction.dot(outward) < 0f32;
+        Self {
+            point,
+            normal: if front_face { shading } else { -shading },
+            outward,
+            front_face,
+            material: object.material_at(part, point),
+        }
+    }
+}
+
+/// Something in the world. Objects are made of parts that are hit and
+/// sampled on their own: spheres and triangles are a single part, `None`,
+/// while meshes have one per triangle.
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Object {
     Sphere(Sphere),
     Triangle(Triangle),
+    Mesh(Mesh),
 }
 
-impl Intersectable for Object {
-    fn get_intersection_point(&self, ray: &Ray) -> Option<Point3> {
-        match self {
-            Self::Triangle(o) => o.get_intersection_point(ray),
-            Self::Sphere(o) => o.get_intersection_point(ray),
-        }
+impl Object {
+    /// The parts of the object.
+    pub fn parts(&self) -> impl Iterator<Item = Option<usize>> {
+        let (whole, triangles) = match self {
+            Self::Mesh(o) => (None, o.triangle_count()),
+            _ => (Some(None), 0),
+        };
+        whole.into_iter().chain((0..triangles).map(Some))
     }
 
-    fn get_roots(&self, ray: &Ray) -> Roots<f32> {
+    /// Distance along `ray` to the nearest hit closer than `t_max`, along
+    /// with the part hit.
+    pub fn hit(&self, ray: &Ray, t_max: f32) -> Option<(f32, Option<usize>)> {
         match self {
-            Self::Triangle(o) => o.get_roots(ray),
-            Self::Sphere(o) => o.get_roots(ray),
+            Self::Sphere(o) => Some((o.hit_distance(ray, t_max)?, None)),
+            Self::Triangle(o) => Some((o.hit_distance(ray, t_max)?, None)),
+            Self::Mesh(o) => o.hit(ray, t_max).map(|(t, triangle)| (t, Some(triangle))),
         }
     }
 
-    fn normal_at(&self, point: Point3) -> Vector3 {
+    /// Whether `ray` hits the object closer than `t_max`.
+    pub fn blocks(&self, ray: &Ray, t_max: f32) -> bool {
         match self {
-            Self::Triangle(o) => o.normal_at(point),
-            Self::Sphere(o) => o.normal_at(point),
+            Self::Mesh(o) => o.blocks(ray, t_max),
+            _ => self.hit(ray, t_max).is_some(),
         }
     }
 
-    fn albedo_at(&self, point: Point3) -> Color {
-        match self {
-            Self::Triangle(o) => o.albedo_at(point),
-            Self::Sphere(o) => o.albedo_at(point),
+    /// Outward normal of `part` at `point`, along with the normal to shade
+    /// with there, which only differs for smooth meshes.
+    pub fn normals_at(&self, part: Option<usize>, point: Point3) -> (Vector3, Vector3) {
+        let outward = match (self, part) {
+            (Self::Sphere(o), _) => o.normal_at(point),
+            (Self::Triangle(o), _) => o.normal_at(point),
+            (Self::Mesh(o), Some(triangle)) => return o.normals_at(triangle, point),
+            (Self::Mesh(_), None) => unreachable!("meshes are hit triangle by triangle"),
+        };
+        (outward, outward)
+    }
+
+    pub fn material_at(&self, part: Option<usize>, point: Point3) -> Material {
+        match (self, part) {
+            (Self::Sphere(o), _) => o.material_at(point),
+            (Self::Triangle(o), _) => o.material_at(point),
+            (Self::Mesh(o), Some(triangle)) => o.material(triangle),
+            (Self::Mesh(_), None) => unreachable!("meshes are hit triangle by triangle"),
         }
     }
 
-    fn roughness_at(&self, point: Point3) -> f32 {
-        match self {
-            Self::Triangle(o) => o.roughness_at(point),
-            Self::Sphere(o) => o.roughness_at(point),
+    pub fn area(&self, part: Option<usize>) -> f32 {
+        match (self, part) {
+            (Self::Sphere(o), _) => o.area(),
+            (Self::Triangle(o), _) => o.area(),
+            (Self::Mesh(o), Some(triangle)) => o.area(triangle),
+            (Self::Mesh(_), None) => unreachable!("meshes are sampled triangle by triangle"),
         }
     }
 
-    fn emission_at(&self, point: Point3) -> f32 {
-        match self {
-            Self::Triangle(o) => o.emission_at(point),
-            Self::Sphere(o) => o.emission_at(point),
+    /// A point picked uniformly over `part`, with the outward normal there.
+    pub fn sample_point(&self, part: Option<usize>, rng: &mut SmallRng) -> (Point3, Vector3) {
+        match (self, part) {
+            (Self::Sphere(o), _) => o.sample_point(rng),
+            (Self::Triangle(o), _) => o.sample_point(rng),
+            (Self::Mesh(o), Some(triangle)) => o.sample_point(triangle, rng),
+            (Self::Mesh(_), None) => unreachable!("meshes are sampled triangle by triangle"),
         }
     }
 }
@@ -75,6 +126,7 @@ impl Bounded for Object {
         match self {
             Self::Sphere(o) => o.aabb(),
             Self::Triangle(o) => o.aabb(),
+            Self::Mesh(o) => o.aabb(),
         }
     }
 }
@@ -84,6 +136,7 @@ impl BHShape for Object {
         match self {
             Self::Sphere(o) => o.bh_node_index(),
             Self::Triangle(o) => o.bh_node_index(),
+            Self::Mesh(o) => o.bh_node_index(),
         }
     }
 
@@ -91,6 +144,7 @@ impl BHShape for Object {
         match self {
             Self::Sphere(o) => o.set_bh_node_index(index),
             Self::Triangle(o) => o.set_bh_node_index(index),
+            Self::Mesh(o) => o.set_bh_node_index(index),
         }
     }
 }
@@ -99,103 +153,37 @@ impl BHShape for Object {
 pub trait Intersectable: Bounded {
     fn get_roots(&self, ray: &Ray) -> Roots<f32>;
     fn normal_at(&self, point: Point3) -> Vector3;
-    fn albedo_at(&self, point: Point3) -> Color;
-    fn roughness_at(&self, point: Point3) -> f32;
-    fn emission_at(&self, point: Point3) -> f32;
-
-    fn get_intersection_point(&self, ray: &Ray) -> Option<Point3> {
+    fn material_at(&self, point: Point3) -> Material;
+    fn area(&self) -> f32;
+    /// A point picked uniformly over the surface, with the outward normal
+    /// there.
+    fn sample_point(&self, rng: &mut SmallRng) -> (Point3, Vector3);
+
+    /// Distance along `ray` to the nearest hit closer than `t_max`.
+    fn hit_distance(&self, ray: &Ray, t_max: f32) -> Option<f32> {
+        let in_range = |t: &f32| (T_MIN..t_max).contains(t);
         match self.get_roots(ray) {
             Roots::No(_) => None,
-            Roots::One([x]) => {
-                if (T_MIN..T_MAX).contains(&x) {
-                    Some(x)
-                } else {
-                    None
-                }
-            }
-            Roots::Two([x, y]) => {
-                let x_in_range = (T_MIN..T_MAX).contains(&x);
-                let y_in_range = (T_MIN..T_MAX).contains(&y);
-                match (x_in_range, y_in_range) {
-                    (true, true) => Some(if x < y { x } else { y }),
-                    (true, false) => Some(x),
-                    (false, true) => Some(y),
-                    (false, false) => None,
-                }
-            }
+            Roots::One([x]) => Some(x).filter(in_range),
+            Roots::Two([x, y]) => [x, y].into_iter().filter(in_range).reduce(f32::min),
             _ => unreachable!(),
         }
-        .map(|x| ray.at(x))
+    }
+
+    fn get_intersection_point(&self, ray: &Ray) -> Option<Point3> {
+        self.hit_distance(ray, T_MAX).map(|t| ray.at(t))
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct WorldList(Vec<Object>);
-pub struct WorldRefList<'a>(Vec<&'a Object>);
 
 impl WorldList {
-    pub fn get<'a>(&'a self) -> &'a Vec<Object> {
+    pub fn get(&self) -> &Vec<Object> {
         &self.0
     }
 
     pub fn from_vec(vec: Vec<Object>) -> Self {
         Self(vec)
     }
-
-    pub fn to_ref<'a>(&'a self) -> WorldRefList {
-        let mut w = Vec::new();
-        for o in self.get() {
-            w.push(o)
-        }
-        WorldRefList::from_vec(w)
-    }
 }
-
-impl<'a> WorldRefList<'a> {
-    pub fn from_vec(vec: Vec<&'a Object>) -> Self {
-        Self(vec)
-    }
-    pub fn intersect(&self, ray: &Ray) -> Option<IntersectionTable> {
-        let intersects: Vec<_> = self
-            .0
-            .iter()
-            .map(|e| (e, e.get_intersection_point(ray)))
-            .map(|e| {
-                if let Some(point) = e.1 {
-                    Some((e.0, point))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        if intersects.iter().all(|e| e.is_none()) {
-            None
-        } else {
-            intersects
-                .iter()
-                .filter_map(|e| *e)
-                .min_by(|a, b| {
-                    (a.1 - ray.origin)
-                        .length()
-                        .partial_cmp(&(b.1 - ray.origin).length())
-                        .unwrap_or(Ordering::Less)
-                })
-                .map(|e| IntersectionTable {
-                    emission: e.0.emission_at(e.1),
-                    point: e.1,
-                    normal: e.0.normal_at(e.1),
-                    albedo: e.0.albedo_at(e.1),
-                    roughness: e.0.roughness_at(e.1),
-                })
-        }
-    }
-}
-
-// #[derive(Debug, PartialEq, Deserialize, Serialize)]
-// pub enum PropertyAt<T>
-// where
-//     T: Clone,
-// {
-//     Value(T),
-//     // FromFunction(fn(Point3) -> T),
-// }
@@ -1,11 +1,13 @@
 use super::Intersectable;
-use crate::color::Color;
+use crate::material::Material;
 use bvh::{
     aabb::{Bounded, AABB},
     bounding_hierarchy::BHShape,
     ray::Ray,
     Point3, Vector3,
 };
+use rand::rngs::SmallRng;
+use rand_distr::{Distribution, UnitSphere};
 use roots::Roots;
 use serde::{Deserialize, Serialize};
 
@@ -14,26 +16,16 @@ pub struct Sphere {
     radius: f32,
     center: Point3,
     node_index: usize,
-    p_albedo_at: Color,
-    p_roughness_at: f32,
-    p_emission_at: f32,
+    material: Material,
 }
 
 impl Sphere {
-    pub fn new(
-        radius: f32,
-        center: Point3,
-        p_roughness_at: f32,
-        p_albedo_at: Color,
-        p_emission_at: f32,
-    ) -> Self {
+    pub fn new(radius: f32, center: Point3, material: Material) -> Self {
         Self {
             radius,
             center,
             node_index: 0,
-            p_albedo_at,
-            p_roughness_at,
-            p_emission_at,
+            material,
         }
     }
 }
@@ -50,15 +42,17 @@ impl Intersectable for Sphere {
         (point - self.center).normalize_or_zero()
     }
 
-    fn roughness_at(&self, _: Point3) -> f32 {
-        self.p_roughness_at
+    fn material_at(&self, _: Point3) -> Material {
+        self.material
     }
 
-    fn albedo_at(&self, _: Point3) -> Color {
-        self.p_albedo_at
+    fn area(&self) -> f32 {
+        4f32 * std::f32::consts::PI * self.radius * self.radius
     }
-    fn emission_at(&self, _: Point3) -> f32 {
-        self.p_emission_at
+
+    fn sample_point(&self, rng: &mut SmallRng) -> (Point3, Vector3) {
+        let normal = Vector3::from_slice(&UnitSphere.sample(rng));
+        (self.center + self.radius * normal, normal)
     }
 }
 
@@ -0,0 +1,290 @@
+//! Encodings for what the controller and slaves send each other. JSON stays
+//! the default so the API can still be poked at with curl; slaves use bincode,
+//! optionally compressed with zstd, which is far smaller for meshes and pixels.
+use displaydoc::Display;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::Read;
+use std::str::FromStr;
+
+pub const JSON: &str = "application/json";
+pub const BINCODE: &str = "application/x-bincode";
+pub const BINCODE_ZSTD: &str = "application/x-bincode+zstd";
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Most a zstd message may inflate to, so a small body can't make us
+/// allocate without bound.
+const MAX_DECOMPRESSED_SIZE: u64 = 1 << 30;
+
+#[derive(Debug, Display)]
+pub enum WireError {
+    /// invalid json: {0}
+    Json(serde_json::Error),
+    /// invalid bincode: {0}
+    Bincode(bincode::Error),
+    /// zstd failed: {0}
+    Zstd(std::io::Error),
+    /// message inflates to more than {0} bytes
+    TooLarge(u64),
+    /// unsupported encoding `{0}`
+    Unsupported(String),
+}
+
+impl std::error::Error for WireError {}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    Bincode,
+    BincodeZstd,
+}
+
+impl Encoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => JSON,
+            Self::Bincode => BINCODE,
+            Self::BincodeZstd => BINCODE_ZSTD,
+        }
+    }
+
+    /// Parses a `Content-Type` header, ignoring parameters such as `charset`.
+    pub fn from_content_type(content_type: &str) -> Result<Self, WireError> {
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            JSON => Ok(Self::Json),
+            BINCODE => Ok(Self::Bincode),
+            BINCODE_ZSTD => Ok(Self::BincodeZstd),
+            other => Err(WireError::Unsupported(other.to_owned())),
+        }
+    }
+
+    /// Picks the first encoding we support from an `Accept` header, falling
+    /// back to JSON.
+    pub fn from_accept(accept: &str) -> Self {
+        accept
+            .split(',')
+            .find_map(|media_type| Self::from_content_type(media_type).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, WireError> {
+        match self {
+            Self::Json => serde_json::to_vec(value).map_err(WireError::Json),
+            Self::Bincode => bincode::serialize(value).map_err(WireError::Bincode),
+            Self::BincodeZstd => {
+                let bytes = bincode::serialize(value).map_err(WireError::Bincode)?;
+                zstd::encode_all(bytes.as_slice(), ZSTD_LEVEL).map_err(WireError::Zstd)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, WireError> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).map_err(WireError::Json),
+            Self::Bincode => bincode::deserialize(bytes).map_err(WireError::Bincode),
+            Self::BincodeZstd => {
+                let bytes = decompress(bytes, MAX_DECOMPRESSED_SIZE)?;
+                bincode::deserialize(&bytes).map_err(WireError::Bincode)
+            }
+        }
+    }
+}
+
+fn decompress(bytes: &[u8], limit: u64) -> Result<Vec<u8>, WireError> {
+    let mut decompressed = Vec::new();
+    zstd::stream::Decoder::new(bytes)
+        .map_err(WireError::Zstd)?
+        .take(limit + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(WireError::Zstd)?;
+    if decompressed.len() as u64 > limit {
+        return Err(WireError::TooLarge(limit));
+    }
+    Ok(decompressed)
+}
+
+/// Accepts the short names used in configuration: `json`, `bincode` and
+/// `zstd`.
+impl FromStr for Encoding {
+    type Err = WireError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "json" => Ok(Self::Json),
+            "bincode" => Ok(Self::Bincode),
+            "zstd" => Ok(Self::BincodeZstd),
+            other => Err(WireError::Unsupported(other.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::camera::CameraSettings;
+    use crate::color::{self, Color};
+    use crate::lights::Light;
+    use crate::material::Material;
+    use crate::render::{World, WorldData};
+    use crate::scene::Background;
+    use crate::shapes::{mesh::Triangle, sphere::Sphere, Object};
+    use crate::{world_hash, ImageSlice, Point3, RenderMeta, WorkItem};
+    use uuid::Uuid;
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::Bincode, Encoding::BincodeZstd];
+
+    fn world() -> Vec<Object> {
+        vec![
+            Object::Sphere(Sphere::new(
+                0.5,
+                Point3::new(0.0, 0.0, -1.0),
+                Material::Metal {
+                    albedo: Color::from_slice([1.0, 0.1, 0.2]),
+                    roughness: 0.1,
+                },
+            )),
+            Object::Triangle(Triangle::new(
+                Point3::new(-1.0, 0.0, -2.0),
+                Point3::new(1.0, 0.0, -2.0),
+                Point3::new(0.0, 1.0 / 3.0, -2.0),
+                Material::Emissive {
+                    color: color::WHITE,
+                    strength: 4.0,
+                },
+            )),
+        ]
+    }
+
+    /// Most wire types don't implement `PartialEq`, so round trips are
+    /// checked by encoding the decoded value again.
+    fn assert_round_trip<T: Serialize + DeserializeOwned>(value: &T) {
+        for encoding in ENCODINGS {
+            let bytes = encoding.encode(value).unwrap();
+            let decoded: T = encoding.decode(&bytes).unwrap();
+            assert_eq!(
+                bincode::serialize(value).unwrap(),
+                bincode::serialize(&decoded).unwrap(),
+                "{:?}",
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn test_object_round_trip() {
+        assert_round_trip(&world());
+    }
+
+    #[test]
+    fn test_world_data_round_trip() {
+        let data = WorldData::build(world());
+        assert_round_trip(&data);
+        let decoded: WorldData = Encoding::BincodeZstd
+            .decode(&Encoding::BincodeZstd.encode(&data).unwrap())
+            .unwrap();
+        assert_eq!(decoded.hash(), data.hash());
+        assert!(World::prebuilt(decoded).is_ok());
+    }
+
+    #[test]
+    fn test_prebuilt_rejects_mismatched_bvh() {
+        let mut data = WorldData::build(world());
+        data.objects.pop();
+        assert!(World::prebuilt(data).is_err());
+    }
+
+    #[test]
+    fn test_work_item_round_trip() {
+        let render_meta = RenderMeta {
+            height: 101,
+            width: 201,
+            tile_size: 32,
+            samples_per_pixel: 64,
+            max_depth: 10,
+            seed: u64::MAX,
+            gamma: 2.2,
+            progressive: true,
+            id: Uuid::new_v4(),
+        };
+        assert_round_trip(&WorkItem {
+            camera: CameraSettings::default().build(render_meta.width, render_meta.height),
+            render_meta,
+            world: world_hash(&world()),
+            background: Background::default(),
+            lights: vec![Light::Spot {
+                position: Point3::new(0.0, 2.0, 0.0),
+                direction: -Point3::Y,
+                color: color::WHITE,
+                intensity: 10.0,
+                angle: 30.0,
+                blend: 0.2,
+            }],
+            tile_no: 27,
+            pass: 2,
+            samples: 12,
+        });
+    }
+
+    #[test]
+    fn test_image_slice_round_trip() {
+        let slice = ImageSlice {
+            tile_no: 3,
+            pass: 1,
+            samples: 3,
+            image: (0..32 * 32 * 3).map(|i| i as f32 / 7.0).collect(),
+            id: Uuid::new_v4(),
+            slave: Uuid::new_v4(),
+        };
+        for encoding in ENCODINGS {
+            let decoded: ImageSlice = encoding.decode(&encoding.encode(&slice).unwrap()).unwrap();
+            assert_eq!(decoded.image, slice.image);
+            assert_eq!(decoded.id, slice.id);
+            assert_eq!(decoded.slave, slice.slave);
+            assert_eq!(
+                (decoded.tile_no, decoded.pass, decoded.samples),
+                (slice.tile_no, slice.pass, slice.samples)
+            );
+        }
+    }
+
+    #[test]
+    fn test_world_hash_survives_json() {
+        let world = world();
+        let decoded: Vec<Object> = Encoding::Json
+            .decode(&Encoding::Json.encode(&world).unwrap())
+            .unwrap();
+        assert_eq!(world_hash(&world), world_hash(&decoded));
+    }
+
+    #[test]
+    fn test_content_type_negotiation() {
+        assert_eq!(
+            Encoding::from_content_type("application/json; charset=utf-8").unwrap(),
+            Encoding::Json
+        );
+        assert!(Encoding::from_content_type("text/plain").is_err());
+        assert_eq!(
+            Encoding::from_accept("text/html, application/x-bincode+zstd, */*"),
+            Encoding::BincodeZstd
+        );
+        assert_eq!(Encoding::from_accept("*/*"), Encoding::Json);
+        for encoding in ENCODINGS {
+            assert_eq!(
+                Encoding::from_content_type(encoding.content_type()).unwrap(),
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn test_zstd_bomb_is_refused() {
+        let bomb = zstd::encode_all(vec![0u8; 1 << 20].as_slice(), ZSTD_LEVEL).unwrap();
+        assert!(bomb.len() < 1 << 10);
+        assert!(matches!(
+            decompress(&bomb, 1 << 16),
+            Err(WireError::TooLarge(65536))
+        ));
+        assert_eq!(decompress(&bomb, 1 << 20).unwrap().len(), 1 << 20);
+    }
+}